@@ -1,48 +1,143 @@
-use ransid::color::Color;
-use std::{mem, io::{self, prelude::*}};
+use super::{color::Color, layout::Rect, terminal::Terminal};
 
-pub const EFFECT_BOLD:      u8 = 1;
-pub const EFFECT_UNDERLINE: u8 = 1 << 1;
+use std::{
+    fmt,
+    io::{self, prelude::*}
+};
+use unicode_segmentation::UnicodeSegmentation;
+
+pub const EFFECT_BOLD:             u16 = 1;
+pub const EFFECT_UNDERLINE:        u16 = 1 << 1;
+pub const EFFECT_ITALIC:           u16 = 1 << 2;
+pub const EFFECT_DIM:              u16 = 1 << 3;
+pub const EFFECT_REVERSE:          u16 = 1 << 4;
+pub const EFFECT_BLINK:            u16 = 1 << 5;
+pub const EFFECT_HIDDEN:           u16 = 1 << 6;
+pub const EFFECT_STRIKETHROUGH:    u16 = 1 << 7;
+pub const EFFECT_UNDERLINE_DOUBLE: u16 = 1 << 8;
+pub const EFFECT_UNDERLINE_CURLY:  u16 = 1 << 9;
+
+/// All underline styles, only one of which is set at a time
+pub const EFFECT_UNDERLINES: u16 = EFFECT_UNDERLINE | EFFECT_UNDERLINE_DOUBLE | EFFECT_UNDERLINE_CURLY;
+
+/// How many chars a cell keeps of a grapheme cluster
+const GRAPHEME_LEN: usize = 8;
+
+/// The text in a cell: A grapheme cluster, like a letter followed by
+/// combining marks, and the number of cells it takes. The cell to the right
+/// of a wide one holds `Grapheme::CONTINUATION`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Grapheme {
+    chars: [char; GRAPHEME_LEN],
+    width: u8
+}
+impl Grapheme {
+    pub const CONTINUATION: Self = Self {
+        chars: ['\0'; GRAPHEME_LEN],
+        width: 0
+    };
+
+    pub const fn new(c: char, width: u8) -> Self {
+        let mut chars = ['\0'; GRAPHEME_LEN];
+        chars[0] = c;
+        Self { chars, width }
+    }
+    pub fn width(&self) -> u8 {
+        self.width
+    }
+    pub fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        self.chars.iter().copied().take_while(|&c| c != '\0')
+    }
+    /// Add `c` to the cluster if it belongs to it, like a combining mark
+    /// does. Returns false if it starts a new one. What doesn't fit is
+    /// dropped.
+    pub fn extend(&mut self, c: char) -> bool {
+        if self.width == 0 {
+            return false;
+        }
+        let mut text: String = self.chars().collect();
+        let len = text.chars().count();
+        text.push(c);
+        if text.graphemes(true).nth(1).is_some() {
+            return false;
+        }
+        if len < GRAPHEME_LEN {
+            self.chars[len] = c;
+        }
+        true
+    }
+}
+impl From<char> for Grapheme {
+    /// A cluster of one narrow character
+    fn from(c: char) -> Self {
+        Self::new(c, 1)
+    }
+}
+impl fmt::Display for Grapheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for c in self.chars() {
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
 
 pub const SPACE: Char = Char {
-    content: ' ',
+    content: Grapheme::new(' ', 1),
     flags: 0,
-    bg: Color::Ansi(0),
-    fg: Color::Ansi(7)
+    bg: Color::Default,
+    fg: Color::Default,
+    underline: Color::Default
 };
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct Char {
-    pub content: char,
-    pub flags: u8,
+    pub content: Grapheme,
+    pub flags: u16,
     pub bg: Color,
-    pub fg: Color
+    pub fg: Color,
+    /// The underline color, where the default is to use `fg`
+    pub underline: Color
 }
 impl From<char> for Char {
     fn from(c: char) -> Self {
         Self {
-            content: c,
-            flags: 0,
-            bg: Color::Ansi(0),
-            fg: Color::Ansi(7)
+            content: c.into(),
+            ..SPACE
         }
     }
 }
-impl Eq for Char {}
-impl PartialEq for Char {
-    fn eq(&self, other: &Char) -> bool {
-        self.content == other.content
-            && self.flags == other.flags
-            && self.bg.as_rgb() == other.bg.as_rgb()
-            && self.fg.as_rgb() == other.fg.as_rgb()
-    }
+
+/// Where the host's cursor is shown, and its DECSCUSR shape
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    pub x: u16,
+    pub y: u16,
+    pub shape: u8
 }
 
 pub struct Buffer {
     prev: (bool, Vec<Char>),
     buf: Vec<Char>,
     pub width: u16,
-    pub height: u16
+    pub height: u16,
+    /// The columns of each row that may have changed since the last draw,
+    /// as a start and an end
+    damage: Vec<Option<(u16, u16)>>,
+    /// Drawing is limited to this
+    clip: Rect,
+    /// Areas to scroll on the host before drawing, and by how many lines
+    scrolls: Vec<(Rect, isize)>,
+    /// Text to put on the host's clipboard on the next draw
+    pub clipboard: Option<String>,
+    /// Whether to ask the host for pointer motion without any button held
+    pub mouse_motion: bool,
+    prev_mouse_motion: bool,
+    /// The cursor, or `None` to hide it
+    pub cursor: Option<Cursor>,
+    prev_cursor: Option<Cursor>,
+    /// How to draw on the host, set with `set_terminal`
+    terminal: Terminal
 }
 impl Buffer {
     pub fn new(width: u16, height: u16) -> Self {
@@ -50,22 +145,93 @@ impl Buffer {
             prev: (false, vec![SPACE; width as usize * height as usize]),
             buf: vec![SPACE; width as usize * height as usize],
             width,
-            height
+            height,
+            damage: vec![Some((0, width)); height as usize],
+            clip: Rect { x: 0, y: 0, width, height },
+            scrolls: Vec::new(),
+            clipboard: None,
+            mouse_motion: false,
+            prev_mouse_motion: false,
+            cursor: None,
+            prev_cursor: None,
+            terminal: Terminal::default()
         }
     }
+    pub fn set_terminal(&mut self, terminal: Terminal) {
+        self.terminal = terminal;
+        self.prev.0 = false;
+    }
+    /// Clear everything inside of the clip
     pub fn clear(&mut self) {
-        for block in &mut self.buf {
-            *block = SPACE;
+        let clip = self.clip;
+        for y in clip.y..clip.y + clip.height {
+            self.line(clip.x, y, clip.width, SPACE);
         }
     }
-    #[cfg(feature = "signals")]
     pub fn resize(&mut self, width: u16, height: u16) {
         self.prev.0 = false;
         self.prev.1.resize(width as usize * height as usize, SPACE);
         self.buf.resize(width as usize * height as usize, SPACE);
-        self.clear();
         self.width = width;
         self.height = height;
+        self.damage = vec![Some((0, width)); height as usize];
+        self.clip = Rect { x: 0, y: 0, width, height };
+        self.clear();
+    }
+    /// Mark an area as changed, so that it gets composed and drawn again
+    pub fn damage(&mut self, rect: Rect) {
+        // Include a cell on each side, in case a wide character there lost
+        // its other half and was blanked out
+        let start = rect.x.saturating_sub(1);
+        let end = (rect.x.saturating_add(rect.width).saturating_add(1)).min(self.width);
+        let rows = rect.y.min(self.height)..rect.y.saturating_add(rect.height).min(self.height);
+        for span in &mut self.damage[rows.start as usize..rows.end as usize] {
+            *span = Some(match *span {
+                Some((old_start, old_end)) => (old_start.min(start), old_end.max(end)),
+                None => (start, end)
+            });
+        }
+    }
+    /// Have the host scroll `rect` up by `lines`, or down if negative,
+    /// rather than draw all of it again. Returns false if it can't.
+    pub fn scroll(&mut self, rect: Rect, lines: isize) -> bool {
+        let screen = Rect { x: 0, y: 0, width: self.width, height: self.height };
+        let partial = rect.x != 0 || rect.width != self.width;
+        if !self.prev.0 || lines == 0 || lines.unsigned_abs() >= rect.height as usize
+                || rect.intersection(screen) != Some(rect) || !self.terminal.can_scroll(partial) {
+            return false;
+        }
+        self.scrolls.push((rect, lines));
+        self.damage(rect);
+        true
+    }
+    /// Returns the damaged areas, where rows with the same damage are
+    /// merged together
+    pub fn damaged(&self) -> Vec<Rect> {
+        let mut rects: Vec<Rect> = Vec::new();
+        for (y, span) in self.damage.iter().enumerate() {
+            let (start, end) = match *span {
+                Some(span) => span,
+                None => continue
+            };
+            match rects.last_mut() {
+                Some(last) if last.y + last.height == y as u16 && (last.x, last.x + last.width) == (start, end) => last.height += 1,
+                _ => rects.push(Rect { x: start, y: y as u16, width: end - start, height: 1 })
+            }
+        }
+        rects
+    }
+    /// Limit drawing to `clip`, or lift the limit if `None`
+    pub fn set_clip(&mut self, clip: Option<Rect>) {
+        let screen = Rect { x: 0, y: 0, width: self.width, height: self.height };
+        self.clip = match clip {
+            Some(clip) => clip.intersection(screen).unwrap_or_default(),
+            None => screen
+        };
+    }
+    /// Returns true if anything on row `y` can be drawn on
+    pub fn row_visible(&self, y: u16) -> bool {
+        y >= self.clip.y && y < self.clip.y + self.clip.height
     }
     pub fn translate(&self, x: u16, y: u16) -> usize {
         if x >= self.width {
@@ -78,92 +244,208 @@ impl Buffer {
         }
         y as usize * self.width as usize + x as usize
     }
+    /// Returns the columns of row `y` that `len` cells from `x` may be
+    /// drawn on
+    fn clipped(&self, x: u16, y: u16, len: u16) -> Option<(u16, u16)> {
+        if !self.row_visible(y) {
+            return None;
+        }
+        let start = x.max(self.clip.x);
+        let end = x.saturating_add(len).min(self.clip.x + self.clip.width);
+        if start >= end {
+            return None;
+        }
+        Some((start, end))
+    }
     pub fn set(&mut self, x: u16, y: u16, val: Char) {
-        let i = self.translate(x, y);
-        if i < self.buf.len() {
+        if self.clipped(x, y, 1).is_some() {
+            let i = self.translate(x, y);
             self.buf[i] = val;
         }
     }
     pub fn line(&mut self, x: u16, y: u16, len: u16, val: Char) {
-        if y >= self.height { return; }
-
-        let start = self.translate(x, y);
-        let end = self.translate(x + len-1, y).min(self.translate(self.width-1, y));
-        for i in start..=end {
-            self.buf[i] = val;
+        if let Some((start, end)) = self.clipped(x, y, len) {
+            let i = self.translate(start, y);
+            self.buf[i..i + (end - start) as usize].fill(val);
         }
     }
     pub fn copy_from(&mut self, x: u16, y: u16, slice: &[Char]) {
-        if y >= self.height { return; }
-
-        let start = self.translate(x, y);
-        let len = slice.len().min(self.width as usize - x as usize);
-        self.buf[start..start+len].copy_from_slice(&slice[..len]);
+        if let Some((start, end)) = self.clipped(x, y, slice.len().min(u16::MAX as usize) as u16) {
+            let i = self.translate(start, y);
+            let from = (start - x) as usize;
+            self.buf[i..i + (end - start) as usize].copy_from_slice(&slice[from..from + (end - start) as usize]);
+        }
     }
 
+    /// Blank out halves of wide characters that lost their other half,
+    /// for example to the border of a window on top or the edge of the
+    /// screen
+    fn fix_wide(&mut self) {
+        for (row, span) in self.buf.chunks_mut(self.width as usize).zip(&self.damage) {
+            if span.is_none() {
+                continue;
+            }
+            for x in 0..row.len() {
+                let broken = match row[x].content.width() {
+                    0 => x == 0 || row[x-1].content.width() != 2,
+                    2 => row.get(x+1).is_none_or(|next| next.content.width() != 0),
+                    _ => false
+                };
+                if broken {
+                    row[x].content = Grapheme::from(' ');
+                }
+            }
+        }
+    }
     pub fn draw<W: Write>(&mut self, w: &mut W) -> io::Result<()> {
-        let (valid, ref prev) = self.prev;
+        self.fix_wide();
+        let valid = self.prev.0;
+
+        // What the host's pen was last set to, if anything, and where the
+        // host's cursor is
+        let mut pen = None;
+        let mut at = None;
+        let mut drawn = false;
 
-        let mut last_bg = None;
-        let mut last_fg = None;
-        let mut last_flags = None;
+        for (rect, lines) in self.scrolls.drain(..) {
+            if !valid {
+                continue;
+            }
+            // New lines should be blank
+            self.terminal.pen(w, pen, &SPACE)?;
+            pen = Some(SPACE);
+            self.terminal.scroll(w, rect, lines, (self.width, self.height))?;
+            drawn = true;
+
+            // Keep track of what the host did
+            let width = self.width as usize;
+            let (start, end) = (rect.x as usize, rect.x as usize + rect.width as usize);
+            let rows: Vec<usize> = (rect.y as usize..(rect.y + rect.height) as usize).collect();
+            let (rows, n) = if lines > 0 {
+                (rows, lines as usize)
+            } else {
+                (rows.into_iter().rev().collect(), lines.unsigned_abs())
+            };
+            for (i, &y) in rows.iter().enumerate() {
+                let row = y * width;
+                match rows.get(i + n) {
+                    Some(&from) => self.prev.1.copy_within(from * width + start..from * width + end, row + start),
+                    None => self.prev.1[row + start..row + end].fill(SPACE)
+                }
+            }
+        }
 
-        'y: for y in 0..self.height {
+        for y in 0..self.height {
+            if valid && self.damage[y as usize].is_none() {
+                continue;
+            }
             let start = y as usize * self.width as usize;
             let end = start + self.width as usize;
-            let mut buf = &self.buf[start..end];
-            let mut prev = &prev[start..end];
+            let (buf, prev) = (&self.buf[start..end], &self.prev.1[start..end]);
+
+            // Both halves of a wide character are drawn if either changed
+            let changed = |x: usize| !valid || buf[x] != prev[x] || match buf[x].content.width() {
+                2 => buf.get(x+1) != prev.get(x+1),
+                _ => false
+            };
 
             let mut x = 0;
-            if valid {
-                while buf[0] == prev[0] {
+            while x < buf.len() {
+                // Unchanged cells, including the rest of the row, are jumped
+                // over when that is shorter than drawing them again
+                if !changed(x) || buf[x].content.width() == 0 {
                     x += 1;
-                    buf = &buf[1..];
-                    prev = &prev[1..];
-
-                    if buf.is_empty() {
-                        continue 'y;
-                    }
+                    continue;
                 }
+                let mut jump = Vec::new();
+                self.terminal.move_to(&mut jump, x as u16, y)?;
+                match at {
+                    Some((at_x, at_y)) if at_y == y && at_x <= x => {
+                        let mut skipped = Vec::new();
+                        let mut skipped_pen = pen;
+                        for c in &buf[at_x..x] {
+                            self.terminal.pen(&mut skipped, skipped_pen, c)?;
+                            skipped_pen = Some(*c);
+                            write!(skipped, "{}", c.content)?;
+                        }
+                        if skipped.len() < jump.len() {
+                            w.write_all(&skipped)?;
+                            pen = skipped_pen;
+                        } else {
+                            w.write_all(&jump)?;
+                        }
+                    },
+                    _ => w.write_all(&jump)?
+                }
+                drawn = true;
+
+                let c = &buf[x];
+                self.terminal.pen(w, pen, c)?;
+                pen = Some(*c);
+                write!(w, "{}", c.content)?;
+                x += c.content.width().max(1) as usize;
+                at = Some((x, y));
             }
+        }
 
-            write!(w, "\x1b[{};{}H", y+1, x+1);
-
-            for col in buf {
-                if last_flags != Some(col.flags) {
-                    write!(w, "\x1b[0m")?;
-                    print_color(w, 48, col.bg)?;
-                    print_color(w, 38, col.fg)?;
-                    if col.flags & EFFECT_BOLD == EFFECT_BOLD {
-                        write!(w, "\x1b[1m")?;
-                    } else if col.flags & EFFECT_UNDERLINE == EFFECT_UNDERLINE {
-                        write!(w, "\x1b[4m")?;
-                    }
-                } else {
-                    if last_bg != Some(col.bg.as_rgb()) {
-                        print_color(w, 48, col.bg)?;
-                        last_bg = Some(col.bg.as_rgb());
-                        last_flags = None;
+        // Drawing moved the cursor, so put it back
+        if !valid || drawn || self.cursor != self.prev_cursor {
+            match self.cursor {
+                Some(cursor) => {
+                    if !valid || self.prev_cursor.map(|prev| prev.shape) != Some(cursor.shape) {
+                        self.terminal.cursor_shape(w, cursor.shape)?;
                     }
-                    if last_fg != Some(col.fg.as_rgb()) {
-                        print_color(w, 38, col.fg)?;
-                        last_fg = Some(col.fg.as_rgb());
-                        last_flags = None;
+                    self.terminal.move_to(w, cursor.x, cursor.y)?;
+                    if !valid || self.prev_cursor.is_none() {
+                        self.terminal.show_cursor(w, true)?;
                     }
+                },
+                None => if !valid || self.prev_cursor.is_some() {
+                    self.terminal.show_cursor(w, false)?;
                 }
-                write!(w, "{}", col.content)?;
             }
+            self.prev_cursor = self.cursor;
+        }
+        if !valid || self.mouse_motion != self.prev_mouse_motion {
+            // Only the focused window can get these, and they are a lot of
+            // traffic, so only ask for them while it wants them
+            self.terminal.mouse_motion(w, self.mouse_motion)?;
+            self.prev_mouse_motion = self.mouse_motion;
+        }
+        if let Some(text) = self.clipboard.take() {
+            self.terminal.set_clipboard(w, &base64(text.as_bytes()))?;
         }
 
+        // The composed buffer is kept, only what was drawn is remembered
+        for (y, span) in self.damage.iter_mut().enumerate() {
+            if valid && span.is_none() {
+                continue;
+            }
+            let start = y * self.width as usize;
+            let end = start + self.width as usize;
+            self.prev.1[start..end].copy_from_slice(&self.buf[start..end]);
+            *span = None;
+        }
         self.prev.0 = true;
-        mem::swap(&mut self.prev.1, &mut self.buf);
         Ok(())
     }
 }
 
-fn print_color<W: Write>(w: &mut W, mode: u8, color: Color) -> io::Result<()> {
-    match color {
-        Color::Ansi(val) => write!(w, "\x1b[{};5;{}m", mode, val),
-        Color::TrueColor(r, g, b) => write!(w, "\x1b[{};2;{};{};{}m", mode, r, g, b)
+fn base64(input: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(TABLE[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                output.push('=');
+            }
+        }
     }
+    output
 }
@@ -0,0 +1,255 @@
+use super::{
+    color::ColorDepth,
+    delaying::DelayingWriter,
+    maybe,
+    protocol::{self, Message, Reader},
+    terminal::Terminal,
+    Result
+};
+
+#[cfg(feature = "signals")]
+use nix::sys::{
+    signal::{self, SigSet},
+    signalfd::{SignalFd, SfdFlags}
+};
+
+use mio::{*, unix::EventedFd};
+use pseudoterm::RawTerminal;
+use std::{
+    env,
+    ffi::{CStr, OsStr},
+    fs::{File, OpenOptions},
+    io::{self, prelude::*},
+    ops::{Deref, DerefMut},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::OpenOptionsExt,
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::UnixStream
+    }
+};
+
+#[cfg(feature = "signals")]
+const TOKEN_SIGNAL: Token = Token(0);
+const TOKEN_STDIN: Token = Token(1);
+const TOKEN_SERVER: Token = Token(2);
+const TOKEN_OUTPUT: Token = Token(3);
+
+/// Open the terminal that `fd` is again, for reading or writing, so that
+/// the new file can be non-blocking without `fd` or whoever else shares it
+/// noticing
+fn reopen(fd: RawFd, write: bool) -> io::Result<File> {
+    let name = unsafe { libc::ttyname(fd) };
+    if name.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let path = OsStr::from_bytes(unsafe { CStr::from_ptr(name) }.to_bytes());
+    OpenOptions::new()
+        .read(!write)
+        .write(write)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOCTTY)
+        .open(path)
+}
+fn set_nonblocking(fd: RawFd, nonblocking: bool) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        let flags = if nonblocking { flags | libc::O_NONBLOCK } else { flags & !libc::O_NONBLOCK };
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// What typing is read from: The terminal stdin is opened again, or stdin
+/// itself if it can't be, like when it's a pipe. Only then is stdin made
+/// non-blocking, and blocking again when this is dropped.
+struct Input(File, bool);
+impl Input {
+    fn open(fd: RawFd) -> io::Result<Self> {
+        if let Ok(file) = reopen(fd, false) {
+            return Ok(Input(file, false));
+        }
+        let dup = unsafe { libc::dup(fd) };
+        if dup < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let file = unsafe { File::from_raw_fd(dup) };
+        set_nonblocking(fd, true)?;
+        Ok(Input(file, true))
+    }
+}
+impl Deref for Input {
+    type Target = File;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl DerefMut for Input {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+impl Drop for Input {
+    fn drop(&mut self) {
+        if self.1 {
+            let _ = set_nonblocking(self.0.as_raw_fd(), false);
+        }
+    }
+}
+
+/// Restores the host terminal when dropped, after whatever is left of the
+/// last frame
+struct Restorer(DelayingWriter<File>, Terminal);
+impl Deref for Restorer {
+    type Target = DelayingWriter<File>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl DerefMut for Restorer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+impl Drop for Restorer {
+    fn drop(&mut self) {
+        let _ = set_nonblocking(self.0.as_raw_fd(), false);
+        let _ = self.1.restore(&mut self.0);
+        let _ = self.0.flush();
+    }
+}
+
+/// Show the server's workspace in this terminal until detached. Colors are
+/// drawn with `colors`, which is what the terminal can show.
+pub fn run(stream: UnixStream, colors: ColorDepth) -> Result<()> {
+    let stdout = io::stdout();
+    let stdin = io::stdin();
+
+    let size = pseudoterm::get_size(&stdout)?;
+    // Frames go through their own non-blocking file, so that a slow
+    // terminal doesn't hold everything else up
+    let output = reopen(stdout.as_raw_fd(), true)?;
+    // Same for typing, so stdin is left blocking for the shell this was
+    // started from even if this is killed
+    let mut input = Input::open(stdin.as_raw_fd())?;
+    let stdout = RawTerminal::new(stdout)?;
+
+    let poll = Poll::new()?;
+    poll.register(&EventedFd(&input.as_raw_fd()), TOKEN_STDIN, Ready::readable(), PollOpt::edge())?;
+
+    stream.set_nonblocking(true)?;
+    poll.register(&EventedFd(&stream.as_raw_fd()), TOKEN_SERVER, Ready::readable() | Ready::writable(), PollOpt::edge())?;
+    let mut server = DelayingWriter::new(stream);
+
+    #[cfg(feature = "signals")]
+    let mut signalfd = {
+        let mut mask = SigSet::empty();
+        mask.add(signal::SIGWINCH); // Window resize event
+        mask.thread_block()?;
+
+        let signalfd = SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK)?;
+        poll.register(&EventedFd(&signalfd.as_raw_fd()), TOKEN_SIGNAL, Ready::readable(), PollOpt::edge())?;
+        signalfd
+    };
+
+    // The server draws for this terminal, and only needs its name to know
+    // how
+    let term = env::var("TERM").unwrap_or_default();
+    let terminal = Terminal::new(&term, colors, false);
+    poll.register(&EventedFd(&output.as_raw_fd()), TOKEN_OUTPUT, Ready::writable(), PollOpt::edge())?;
+    let mut output = Restorer(DelayingWriter::new(output), terminal);
+    output.1.start(&mut output.0)?;
+
+    server.push(&Message::Attach { cols: size.cols, rows: size.rows, colors, term }.to_bytes())?;
+
+    let mut reader = Reader::new();
+    let mut events = Events::with_capacity(1024);
+    let mut buf = [0; 1024];
+    // Whether the server may have sent something that wasn't read yet
+    let mut unread = false;
+    // Same for stdin
+    let mut typed = false;
+    // Whether a frame is still being written to the terminal
+    let mut drawing = false;
+    let detached = 'main: loop {
+        poll.poll(&mut events, None)?;
+
+        for event in events.iter() {
+            match event.token() {
+                #[cfg(feature = "signals")]
+                TOKEN_SIGNAL => while signalfd.read_signal()?.is_some() {
+                    let size = pseudoterm::get_size(&*stdout)?;
+                    server.push(&Message::Resize { cols: size.cols, rows: size.rows }.to_bytes())?;
+                },
+                TOKEN_SERVER => {
+                    if event.readiness().is_writable() {
+                        server.write_todo()?;
+                    }
+                    unread |= event.readiness().is_readable();
+                },
+                // Hangups count too, reading tells which it was
+                TOKEN_STDIN => typed = true,
+                TOKEN_OUTPUT => {
+                    output.write_todo()?;
+                },
+                _ => ()
+            }
+        }
+
+        if unread {
+            unread = false;
+            let open = reader.read_from(&mut *server)?;
+            while let Some(message) = reader.next()? {
+                match message {
+                    Message::Output(frame) => {
+                        output.push(&frame)?;
+                        drawing = true;
+                    },
+                    Message::Detached => break 'main true,
+                    Message::Exit => break 'main false,
+                    _ => ()
+                }
+            }
+            if !open {
+                break 'main false;
+            }
+        }
+        // The server sends the next frame once this one is all out, and
+        // skips frames until then if the terminal is slow
+        if drawing && output.is_empty() {
+            drawing = false;
+            server.push(&Message::Drawn.to_bytes())?;
+        }
+
+        // Typing waits while the server isn't keeping up, until the socket
+        // is writable again
+        while typed && !server.is_full() {
+            let n = match maybe(input.read(&mut buf)) {
+                Ok(Some(n)) => n,
+                Ok(None) => {
+                    typed = false;
+                    break;
+                },
+                Err(ref err) if err.raw_os_error() == Some(libc::EIO) => 0,
+                Err(err) => return Err(err.into())
+            };
+            if n == 0 {
+                // The terminal hung up. Windows are left running for
+                // whoever attaches next, and there is nobody to tell.
+                server.push(&Message::Detach.to_bytes())?;
+                break 'main false;
+            }
+            protocol::write_input(&mut server, &buf[..n])?;
+        }
+    };
+
+    drop(output);
+    drop(stdout);
+    if detached {
+        println!("[detached]");
+    }
+    Ok(())
+}
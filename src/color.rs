@@ -0,0 +1,152 @@
+use std::env;
+use terminfo::{capability::MaxColors, Database};
+
+/// The first 16 colors as xterm shows them by default
+const PALETTE: [(u8, u8, u8); 16] = [
+    (0, 0, 0), (205, 0, 0), (0, 205, 0), (205, 205, 0),
+    (0, 0, 238), (205, 0, 205), (0, 205, 205), (229, 229, 229),
+    (127, 127, 127), (255, 0, 0), (0, 255, 0), (255, 255, 0),
+    (92, 92, 255), (255, 0, 255), (0, 255, 255), (255, 255, 255)
+];
+/// The levels of each component in the 6x6x6 cube of the 256 color palette
+const CUBE: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// How many colors the host terminal can show
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ColorDepth {
+    Ansi8,
+    Ansi16,
+    Ansi256,
+    TrueColor
+}
+impl ColorDepth {
+    /// Parse the argument of `--colors`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "8" => Some(ColorDepth::Ansi8),
+            "16" => Some(ColorDepth::Ansi16),
+            "256" => Some(ColorDepth::Ansi256),
+            "truecolor" | "24bit" => Some(ColorDepth::TrueColor),
+            _ => None
+        }
+    }
+    fn from_count(colors: i32) -> Self {
+        match colors {
+            n if n >= 1 << 24 => ColorDepth::TrueColor,
+            n if n >= 256 => ColorDepth::Ansi256,
+            n if n >= 16 => ColorDepth::Ansi16,
+            _ => ColorDepth::Ansi8
+        }
+    }
+    /// Guess what the terminal this runs in can do, from `$COLORTERM` and
+    /// the terminfo entry of `$TERM`
+    pub fn detect() -> Self {
+        if let Ok("truecolor") | Ok("24bit") = env::var("COLORTERM").as_deref() {
+            return ColorDepth::TrueColor;
+        }
+        if let Some(MaxColors(colors)) = Database::from_env().ok().and_then(|db| db.get::<MaxColors>()) {
+            return Self::from_count(colors);
+        }
+        match env::var("TERM") {
+            Ok(ref term) if term.ends_with("-direct") => ColorDepth::TrueColor,
+            Ok(ref term) if term.ends_with("-256color") => ColorDepth::Ansi256,
+            _ => ColorDepth::Ansi16
+        }
+    }
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        [ColorDepth::Ansi8, ColorDepth::Ansi16, ColorDepth::Ansi256, ColorDepth::TrueColor]
+            .get(byte as usize)
+            .copied()
+    }
+}
+
+/// The color of a cell. Unlike ransid's, this can be left to the host's
+/// theme.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Color {
+    /// Whatever the host terminal's default is
+    #[default]
+    Default,
+    /// An index into the 256 color palette
+    Ansi(u8),
+    Rgb(u8, u8, u8)
+}
+impl Color {
+    /// Returns the components of a palette color, assuming xterm's palette
+    fn rgb(index: u8) -> (u8, u8, u8) {
+        match index {
+            0..=15 => PALETTE[index as usize],
+            16..=231 => {
+                let i = index - 16;
+                (CUBE[i as usize / 36], CUBE[i as usize / 6 % 6], CUBE[i as usize % 6])
+            },
+            _ => {
+                let level = 8 + 10 * (index - 232);
+                (level, level, level)
+            }
+        }
+    }
+    /// Returns the closest color in the 256 color palette, which is either
+    /// in the cube or on the gray ramp
+    fn nearest_256(r: u8, g: u8, b: u8) -> u8 {
+        let level = |v: u8| match v {
+            0..=47 => 0,
+            48..=114 => 1,
+            _ => (v - 35) / 40
+        };
+        let (cr, cg, cb) = (level(r), level(g), level(b));
+        let cube = (CUBE[cr as usize], CUBE[cg as usize], CUBE[cb as usize]);
+
+        let average = (r as u32 + g as u32 + b as u32) / 3;
+        let gray = if average > 238 { 23 } else { (average.saturating_sub(3) / 10) as u8 };
+        let level = 8 + 10 * gray;
+
+        if distance((r, g, b), cube) <= distance((r, g, b), (level, level, level)) {
+            16 + 36 * cr + 6 * cg + cb
+        } else {
+            232 + gray
+        }
+    }
+    /// Returns the closest color the host can show
+    pub fn downsample(self, depth: ColorDepth) -> Self {
+        let rgb = match (self, depth) {
+            (Color::Default, _) | (_, ColorDepth::TrueColor) | (Color::Ansi(_), ColorDepth::Ansi256) => return self,
+            (Color::Rgb(r, g, b), ColorDepth::Ansi256) => return Color::Ansi(Self::nearest_256(r, g, b)),
+            (Color::Ansi(0..=7), _) | (Color::Ansi(8..=15), ColorDepth::Ansi16) => return self,
+            // Terminals with 8 colors often show the bright ones for bold
+            // text, so these are the closest
+            (Color::Ansi(index @ 8..=15), _) => return Color::Ansi(index - 8),
+            (Color::Ansi(index), _) => Self::rgb(index),
+            (Color::Rgb(r, g, b), _) => (r, g, b)
+        };
+        let colors = if depth == ColorDepth::Ansi8 { 8 } else { 16 };
+        let nearest = (0..colors).min_by_key(|&i| distance(rgb, PALETTE[i as usize])).unwrap_or(0);
+        Color::Ansi(nearest)
+    }
+    /// Returns the SGR parameter that sets this as the text (38), background
+    /// (48) or underline (58) color, on a host with `depth` that the color
+    /// already fits
+    pub fn sgr(self, mode: u8, depth: ColorDepth) -> String {
+        match self {
+            Color::Default => (mode + 1).to_string(),
+            // Terminals that know underline colors all know the colon form,
+            // and the others shouldn't mistake it for something else
+            Color::Ansi(val) if mode == 58 => format!("58:5:{}", val),
+            Color::Rgb(r, g, b) if mode == 58 => format!("58:2::{}:{}:{}", r, g, b),
+            // 30-37, 90-97 and their backgrounds are all that limited
+            // terminals know
+            Color::Ansi(val @ 0..=7) if depth < ColorDepth::Ansi256 => (mode - 8 + val).to_string(),
+            Color::Ansi(val @ 8..=15) if depth < ColorDepth::Ansi256 => (mode + 52 + val - 8).to_string(),
+            Color::Ansi(val) => format!("{};5;{}", mode, val),
+            Color::Rgb(r, g, b) => format!("{};2;{};{};{}", mode, r, g, b)
+        }
+    }
+}
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
@@ -0,0 +1,299 @@
+use super::{
+    color::Color,
+    input::{self, Action, Command, DEFAULT_PREFIX},
+    key::Key,
+    Result
+};
+
+use serde::{de::{self, Deserializer}, Deserialize};
+use std::{
+    collections::HashMap,
+    env,
+    ffi::OsString,
+    fs,
+    io,
+    num::NonZeroU32,
+    path::PathBuf,
+    process::Command as Process
+};
+use unicode_width::UnicodeWidthChar;
+
+/// Everything that can be set in `$XDG_CONFIG_HOME/termwm/config.toml`.
+/// Anything left out keeps the built-in behaviour.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The program new windows run, instead of `$SHELL`
+    pub shell: Option<PathBuf>,
+    pub args: Vec<String>,
+    /// Most frames to draw per second. Nothing is drawn while nothing
+    /// changes.
+    pub fps: NonZeroU32,
+    /// Whether the host terminal has left and right margins (DECSLRM), so
+    /// that windows narrower than the screen can be scrolled on it
+    pub scroll_margins: bool,
+    pub ambiguous_width: AmbiguousWidth,
+    pub window: WindowConfig,
+    pub border: BorderStyle,
+    pub colors: Colors,
+    pub keys: Keys,
+    pub mouse: Mouse
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            shell: None,
+            args: Vec::new(),
+            fps: NonZeroU32::new(60).unwrap(),
+            scroll_margins: false,
+            ambiguous_width: AmbiguousWidth::default(),
+            window: WindowConfig::default(),
+            border: BorderStyle::default(),
+            colors: Colors::default(),
+            keys: Keys::default(),
+            mouse: Mouse::default()
+        }
+    }
+}
+impl Config {
+    /// Returns where the config file is read from
+    pub fn path() -> Option<PathBuf> {
+        let dir = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(dir.join("termwm").join("config.toml"))
+    }
+    /// Read the config file, or use the defaults if there is none
+    pub fn load() -> Result<Self> {
+        let path = match Self::path() {
+            Some(path) => path,
+            None => return Ok(Self::default())
+        };
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err.into())
+        };
+        toml::from_str(&content).map_err(|err| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), err)
+        ).into())
+    }
+    /// Returns the command new windows run
+    pub fn shell(&self) -> Process {
+        let shell = self.shell.clone().map(OsString::from)
+            .or_else(|| env::var_os("SHELL"))
+            .unwrap_or_else(|| OsString::from("bash"));
+        let mut cmd = Process::new(shell);
+        cmd.args(&self.args);
+        cmd
+    }
+}
+
+/// How many cells characters of ambiguous width take, like `°` and box
+/// drawing characters, which are wide in CJK fonts. This has to agree with
+/// the host terminal.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AmbiguousWidth {
+    #[default]
+    Narrow,
+    Wide
+}
+impl AmbiguousWidth {
+    /// Returns how many cells `c` takes, which is 0 for combining marks
+    pub fn width(self, c: char) -> usize {
+        match self {
+            AmbiguousWidth::Narrow => c.width(),
+            AmbiguousWidth::Wide => c.width_cjk()
+        }.unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Placement {
+    /// In the middle of the screen
+    #[default]
+    Center,
+    /// A bit below and to the right of the top window
+    Cascade,
+    /// Where the mouse was clicked, if it was
+    Pointer
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    /// Size of new windows, including the border
+    pub width: u16,
+    pub height: u16,
+    pub placement: Placement,
+    /// Most bytes per second read from each window's program, so that
+    /// one that runs away can't freeze everything else
+    pub throughput: Option<NonZeroU32>
+}
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            width: 80,
+            height: 32,
+            placement: Placement::default(),
+            throughput: None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BorderStyle {
+    #[default]
+    Single,
+    Rounded,
+    Double,
+    Heavy,
+    Ascii
+}
+impl BorderStyle {
+    /// Returns the top left, top right, bottom left and bottom right
+    /// corners, followed by the horizontal and vertical lines
+    pub fn glyphs(self) -> [char; 6] {
+        match self {
+            BorderStyle::Single => ['┌', '┐', '└', '┘', '─', '│'],
+            BorderStyle::Rounded => ['╭', '╮', '╰', '╯', '─', '│'],
+            BorderStyle::Double => ['╔', '╗', '╚', '╝', '═', '║'],
+            BorderStyle::Heavy => ['┏', '┓', '┗', '┛', '━', '┃'],
+            BorderStyle::Ascii => ['+', '+', '+', '+', '-', '|']
+        }
+    }
+}
+
+/// Either an index into the 256 color palette, `"#rrggbb"` or `"default"`
+fn color<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Color, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Ansi(u8),
+        Name(String)
+    }
+    match Value::deserialize(deserializer)? {
+        Value::Ansi(i) => Ok(Color::Ansi(i)),
+        Value::Name(ref name) if name == "default" => Ok(Color::Default),
+        Value::Name(hex) => {
+            let digits = hex.strip_prefix('#')
+                .filter(|digits| digits.len() == 6)
+                .and_then(|digits| u32::from_str_radix(digits, 16).ok());
+            match digits {
+                Some(rgb) => Ok(Color::Rgb((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)),
+                None => Err(de::Error::custom(format!("invalid color {:?}, expected 0-255, \"#rrggbb\" or \"default\"", hex)))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Colors {
+    #[serde(deserialize_with = "color")]
+    pub border: Color,
+    #[serde(deserialize_with = "color")]
+    pub focused_border: Color,
+    #[serde(deserialize_with = "color")]
+    pub title: Color,
+    /// Drawn on top of `focused_border`
+    #[serde(deserialize_with = "color")]
+    pub focused_title: Color
+}
+
+fn parse_key<E: de::Error>(name: &str) -> std::result::Result<Key, E> {
+    Key::from_name(name).ok_or_else(|| E::custom(format!("invalid key {:?}", name)))
+}
+fn key<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Key, D::Error> {
+    parse_key(&String::deserialize(deserializer)?)
+}
+fn bindings<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<HashMap<Key, Action>, D::Error> {
+    let mut bindings = input::default_bindings();
+    for (name, command) in HashMap::<String, String>::deserialize(deserializer)? {
+        let key = parse_key(&name)?;
+        if command == "none" {
+            bindings.remove(&key);
+            continue;
+        }
+        let action = Action::from_name(&command)
+            .ok_or_else(|| de::Error::custom(format!("unknown command {:?}", command)))?;
+        bindings.insert(key, action);
+    }
+    Ok(bindings)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Keys {
+    #[serde(deserialize_with = "key")]
+    pub prefix: Key,
+    /// Keys after the prefix, on top of the default ones. Binding a key to
+    /// `"none"` unbinds it.
+    #[serde(deserialize_with = "bindings")]
+    pub bind: HashMap<Key, Action>,
+    /// Milliseconds the arrow keys keep moving or resizing after the prefix
+    /// without pressing it again
+    pub repeat_time: u64
+}
+impl Default for Keys {
+    fn default() -> Self {
+        Self {
+            prefix: DEFAULT_PREFIX,
+            bind: input::default_bindings(),
+            repeat_time: 500
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Modifier {
+    Shift,
+    Alt,
+    Ctrl
+}
+impl Modifier {
+    /// Returns the bit the modifier sets in a mouse report
+    pub fn mask(self) -> u8 {
+        match self {
+            Modifier::Shift => 4,
+            Modifier::Alt => 8,
+            Modifier::Ctrl => 16
+        }
+    }
+}
+
+fn command<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<Command>, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    if name == "none" {
+        return Ok(None);
+    }
+    Command::from_name(&name)
+        .map(Some)
+        .ok_or_else(|| de::Error::custom(format!("unknown command {:?}", name)))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mouse {
+    /// Held while dragging to select text
+    pub select: Modifier,
+    /// What clicking on the empty background does
+    #[serde(deserialize_with = "command")]
+    pub background: Option<Command>,
+    /// How many lines one step of the wheel scrolls
+    pub scroll_lines: u16
+}
+impl Default for Mouse {
+    fn default() -> Self {
+        Self {
+            select: Modifier::Alt,
+            background: Some(Command::Spawn),
+            scroll_lines: 3
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use super::{
+    delaying::DelayingWriter,
+    layout::{Layout, Rect},
+    maybe,
+    protocol,
+    window::Window,
+    workspace::Workspace,
+    Result
+};
+
+use mio::{*, unix::EventedFd};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt::Display,
+    io::{self, prelude::*, BufReader},
+    os::unix::{
+        io::AsRawFd,
+        net::{UnixListener, UnixStream}
+    },
+    process::{self, Command}
+};
+
+const USAGE: &str = "\
+usage: termwm msg list
+       termwm msg spawn [X Y WIDTH HEIGHT] [-- COMMAND [ARGS...]]
+       termwm msg move ID X Y
+       termwm msg resize ID WIDTH HEIGHT
+       termwm msg raise ID
+       termwm msg close ID
+       termwm msg send ID TEXT
+       termwm msg capture ID [--scrollback]
+       termwm msg '{\"cmd\": ...}'";
+
+/// A request to the control socket. Each request is one JSON object on its
+/// own line, and gets a JSON object on one line back with `ok` set to
+/// whether it worked. Geometry always includes the window border, and is
+/// clamped to the screen.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+pub enum Request {
+    List,
+    Spawn {
+        #[serde(default)]
+        command: Vec<String>,
+        x: Option<u16>,
+        y: Option<u16>,
+        width: Option<u16>,
+        height: Option<u16>
+    },
+    Move { id: usize, x: u16, y: u16 },
+    Resize { id: usize, width: u16, height: u16 },
+    Raise { id: usize },
+    Close { id: usize },
+    Send { id: usize, input: String },
+    Capture {
+        id: usize,
+        #[serde(default)]
+        scrollback: bool
+    }
+}
+
+fn fail<E: Display>(err: E) -> String {
+    err.to_string()
+}
+
+/// Run a request against the workspace and return the response
+pub fn handle(workspace: &mut Workspace, line: &[u8]) -> Value {
+    let result = serde_json::from_slice(line)
+        .map_err(fail)
+        .and_then(|request| perform(workspace, request));
+
+    match result {
+        Ok(Value::Null) => json!({ "ok": true }),
+        Ok(mut value) => {
+            value["ok"] = Value::Bool(true);
+            value
+        },
+        Err(err) => json!({ "ok": false, "error": err })
+    }
+}
+
+fn window<'w>(workspace: &'w mut Workspace, id: usize) -> std::result::Result<&'w mut Window, String> {
+    workspace.windows.get_mut(&Token(id))
+        .ok_or_else(|| format!("no window with id {}", id))
+}
+
+fn perform(workspace: &mut Workspace, request: Request) -> std::result::Result<Value, String> {
+    let floating = workspace.layout == Layout::Floating;
+    let (screen_width, screen_height) = (workspace.buffer.width, workspace.buffer.height);
+    // Windows start on the screen and are at most as big as it
+    let (max_x, max_y) = (screen_width - 1, screen_height - 1);
+    let (max_width, max_height) = (screen_width.max(3), screen_height.max(3));
+
+    match request {
+        Request::List => {
+            let windows: Vec<Value> = workspace.windows.iter().map(|(&key, window)| json!({
+                "id": usize::from(key),
+                "pid": window.child.id(),
+                "title": window.title,
+                "x": window.x,
+                "y": window.y,
+                "width": window.width + 2,
+                "height": window.height + 2,
+                "focused": workspace.focus == Some(key)
+            })).collect();
+            Ok(json!({ "windows": windows }))
+        },
+        Request::Spawn { command, x, y, width, height } => {
+            let default = workspace.default_frame(None);
+            let frame = Rect {
+                x: x.unwrap_or(default.x).min(max_x),
+                y: y.unwrap_or(default.y).min(max_y),
+                width: width.unwrap_or(default.width).clamp(3, max_width),
+                height: height.unwrap_or(default.height).clamp(3, max_height)
+            };
+            let mut cmd = match command.split_first() {
+                Some((program, args)) => {
+                    let mut cmd = Command::new(program);
+                    cmd.args(args);
+                    cmd
+                },
+                None => workspace.config.shell()
+            };
+            let key = workspace.spawn_at(&mut cmd, frame).map_err(fail)?;
+            Ok(json!({ "id": usize::from(key) }))
+        },
+        Request::Move { id, x, y } => {
+            let window = window(workspace, id)?;
+            if !floating {
+                return Err(String::from("windows can only be moved in the floating layout"));
+            }
+            window.x = x.min(max_x);
+            window.y = y.min(max_y);
+            Ok(Value::Null)
+        },
+        Request::Resize { id, width, height } => {
+            let window = window(workspace, id)?;
+            if !floating {
+                return Err(String::from("windows can only be resized in the floating layout"));
+            }
+            window.resize(width.clamp(3, max_width) - 2, height.clamp(3, max_height) - 2).map_err(fail)?;
+            Ok(Value::Null)
+        },
+        Request::Raise { id } => {
+            window(workspace, id)?;
+            workspace.raise(Token(id));
+            workspace.arrange().map_err(fail)?;
+            Ok(Value::Null)
+        },
+        Request::Close { id } => {
+            window(workspace, id)?.close().map_err(fail)?;
+            Ok(Value::Null)
+        },
+        Request::Send { id, input } => {
+            let window = window(workspace, id)?;
+            if window.pty.is_full() {
+                return Err(String::from("window isn't reading its input"));
+            }
+            window.pty.write_all(input.as_bytes())
+                .and_then(|()| window.pty.flush())
+                .map_err(fail)?;
+            Ok(Value::Null)
+        },
+        Request::Capture { id, scrollback } => {
+            let text = window(workspace, id)?.text(scrollback);
+            Ok(json!({ "text": text }))
+        }
+    }
+}
+
+struct Control {
+    stream: DelayingWriter<UnixStream>,
+    buf: Vec<u8>
+}
+
+/// All connections to the control socket
+pub struct Controls {
+    map: HashMap<Token, Control>,
+    next: Token
+}
+impl Controls {
+    pub fn new(first: Token) -> Self {
+        Self {
+            map: HashMap::new(),
+            next: first
+        }
+    }
+    pub fn accept(&mut self, poll: &Poll, listener: &UnixListener) -> Result<()> {
+        while let Some((stream, _)) = maybe(listener.accept())? {
+            stream.set_nonblocking(true)?;
+            poll.register(&EventedFd(&stream.as_raw_fd()), self.next, Ready::readable() | Ready::writable(), PollOpt::edge())?;
+
+            self.map.insert(self.next, Control {
+                stream: DelayingWriter::new(stream),
+                buf: Vec::new()
+            });
+            self.next = Token(usize::from(self.next) + 1);
+        }
+        Ok(())
+    }
+    /// Handle an event for one of the connections
+    pub fn ready(&mut self, workspace: &mut Workspace, token: Token, readiness: Ready) {
+        let control = match self.map.get_mut(&token) {
+            Some(control) => control,
+            None => return
+        };
+
+        let mut closed = readiness.is_writable() && control.stream.write_todo().is_err();
+        if readiness.is_readable() {
+            let mut buf = [0; 1024];
+            loop {
+                match maybe(control.stream.read(&mut buf)) {
+                    Ok(Some(0)) | Err(_) => {
+                        closed = true;
+                        break;
+                    },
+                    Ok(None) => break,
+                    Ok(Some(n)) => control.buf.extend_from_slice(&buf[..n])
+                }
+            }
+        }
+
+        while let Some(end) = control.buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = control.buf.drain(..=end).collect();
+            let line = &line[..end];
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+
+            let response = handle(workspace, line);
+            if writeln!(control.stream, "{}", response).and_then(|()| control.stream.flush()).is_err() {
+                closed = true;
+                break;
+            }
+        }
+
+        if closed {
+            if let Some(control) = self.map.remove(&token) {
+                let _ = workspace.poll.deregister(&EventedFd(&control.stream.as_raw_fd()));
+            }
+        }
+    }
+}
+
+fn usage() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, USAGE)
+}
+
+/// Turn the arguments of `termwm msg` into a request
+fn request(args: &[OsString]) -> io::Result<Value> {
+    let args = args.iter()
+        .map(|arg| arg.to_str().ok_or_else(usage))
+        .collect::<io::Result<Vec<&str>>>()?;
+    let number = |i: usize| -> io::Result<u64> {
+        args.get(i).and_then(|arg| arg.parse().ok()).ok_or_else(usage)
+    };
+
+    Ok(match args.first().copied() {
+        Some(raw) if raw.starts_with('{') => serde_json::from_str(raw)?,
+        Some("list") => json!({ "cmd": "list" }),
+        Some("spawn") => {
+            let split = args.iter().position(|&arg| arg == "--").unwrap_or(args.len());
+            let command = args.get(split + 1..).unwrap_or(&[]);
+
+            let mut request = json!({ "cmd": "spawn", "command": command });
+            match split {
+                1 => (),
+                5 => for (i, key) in ["x", "y", "width", "height"].iter().enumerate() {
+                    request[*key] = json!(number(1 + i)?);
+                },
+                _ => return Err(usage())
+            }
+            request
+        },
+        Some("move") => json!({ "cmd": "move", "id": number(1)?, "x": number(2)?, "y": number(3)? }),
+        Some("resize") => json!({ "cmd": "resize", "id": number(1)?, "width": number(2)?, "height": number(3)? }),
+        Some("raise") => json!({ "cmd": "raise", "id": number(1)? }),
+        Some("close") => json!({ "cmd": "close", "id": number(1)? }),
+        Some("send") => json!({ "cmd": "send", "id": number(1)?, "input": args.get(2).ok_or_else(usage)? }),
+        Some("capture") => json!({
+            "cmd": "capture",
+            "id": number(1)?,
+            "scrollback": args.get(2) == Some(&"--scrollback")
+        }),
+        _ => return Err(usage())
+    })
+}
+
+/// The `termwm msg` command: Send one request and print the response
+pub fn message(args: &[OsString]) -> Result<()> {
+    let request = match request(args) {
+        Ok(request) => request,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(2);
+        }
+    };
+
+    let mut stream = UnixStream::connect(protocol::control_path()?)?;
+    writeln!(stream, "{}", request)?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    print!("{}", response);
+
+    // The error is already in the printed response
+    let response: Value = serde_json::from_str(&response)?;
+    if response["ok"] != Value::Bool(true) {
+        process::exit(1);
+    }
+    Ok(())
+}
@@ -5,6 +5,15 @@ use std::{
     ops::{Deref, DerefMut}
 };
 
+/// How much is kept around for a writer that isn't keeping up
+const LIMIT: usize = 64 * 1024;
+
+/// Keeps whatever a non-blocking writer couldn't take for later. Once
+/// `LIMIT` bytes are waiting it reports being full, and `write` fails with
+/// `WouldBlock` until `write_todo` has made room. Whoever writes to it
+/// should hold off until then, or drop what they were going to write if it
+/// can't wait. A write that starts below the limit is taken whole, so that
+/// a message written at once is never cut short.
 pub struct DelayingWriter<W: Write> {
     inner: W,
     todo: Vec<u8>
@@ -13,9 +22,20 @@ impl<W: Write> DelayingWriter<W> {
     pub fn new(inner: W) -> Self {
         Self { inner, todo: Vec::new() }
     }
+    /// Returns true if nothing more should be written until the inner
+    /// writer has taken some of what is waiting
+    pub fn is_full(&self) -> bool {
+        self.todo.len() >= LIMIT
+    }
+    /// Returns true if everything written has been passed on
+    pub fn is_empty(&self) -> bool {
+        self.todo.is_empty()
+    }
     pub fn write_todo(&mut self) -> io::Result<bool> {
         let mut written = false;
-        loop {
+        // Even an empty write wakes up a pty, which would make it report
+        // being writable again and again
+        while !self.todo.is_empty() {
             match maybe(self.inner.write(&self.todo))? {
                 None | Some(0) => break,
                 Some(n) => {
@@ -26,9 +46,9 @@ impl<W: Write> DelayingWriter<W> {
         }
         Ok(written)
     }
-}
-impl<W: Write> Write for DelayingWriter<W> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    /// Write all of `buf` even if that goes over the limit, for what can
+    /// neither wait nor be dropped and is kept small some other way
+    pub fn push(&mut self, buf: &[u8]) -> io::Result<()> {
         self.write_todo()?;
 
         let mut written = 0;
@@ -45,7 +65,16 @@ impl<W: Write> Write for DelayingWriter<W> {
         if written < buf.len() {
             self.todo.extend_from_slice(&buf[written..]);
         }
-
+        Ok(())
+    }
+}
+impl<W: Write> Write for DelayingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_todo()?;
+        if self.is_full() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.push(buf)?;
         Ok(buf.len())
     }
     fn flush(&mut self) -> io::Result<()> {
@@ -64,3 +93,59 @@ impl<W: Write> DerefMut for DelayingWriter<W> {
         &mut self.inner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pseudoterm::OpenptyOptions;
+    use std::{fs::File, os::unix::io::AsRawFd};
+
+    #[test]
+    fn full_pty() {
+        let (pty, mut slave) = pseudoterm::openpty(&OpenptyOptions::new().with_nonblocking(true)).unwrap();
+        // Nothing reads the echo, and the bytes should arrive as they are
+        let fd = slave.as_raw_fd();
+        unsafe {
+            let mut termios = std::mem::zeroed();
+            assert_eq!(libc::tcgetattr(fd, &mut termios), 0);
+            libc::cfmakeraw(&mut termios);
+            assert_eq!(libc::tcsetattr(fd, libc::TCSANOW, &termios), 0);
+            assert_eq!(libc::fcntl(fd, libc::F_SETFL, libc::fcntl(fd, libc::F_GETFL) | libc::O_NONBLOCK), 0);
+        }
+        let mut pty = DelayingWriter::new(pty);
+
+        let input: Vec<u8> = (0..4 * LIMIT).map(|i| (i % 251) as u8).collect();
+        let mut sent = 0;
+        let write = |pty: &mut DelayingWriter<File>, sent: &mut usize| {
+            while *sent < input.len() {
+                let end = input.len().min(*sent + 1000);
+                match maybe(pty.write(&input[*sent..end])).unwrap() {
+                    Some(n) => *sent += n,
+                    None => {
+                        assert!(pty.is_full());
+                        break;
+                    }
+                }
+            }
+        };
+
+        // Nobody is reading, so this stops once the limit is reached
+        write(&mut pty, &mut sent);
+        assert!(sent >= LIMIT && sent < input.len());
+
+        let mut received = Vec::new();
+        let mut buf = [0; 4096];
+        for _ in 0..100_000 {
+            if received.len() == input.len() {
+                break;
+            }
+            pty.write_todo().unwrap();
+            while let Some(n) = maybe(slave.read(&mut buf)).unwrap() {
+                received.extend_from_slice(&buf[..n]);
+            }
+            write(&mut pty, &mut sent);
+        }
+        assert!(pty.is_empty());
+        assert!(received == input);
+    }
+}
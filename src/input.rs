@@ -1,74 +1,274 @@
-use super::Result;
-
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum State {
-    Normal,
-    ESC,
-    CSI,
-    Mouse
+use super::{
+    key::{Key, KeyCode, Mouse, Token, Tokenizer, MOD_CTRL},
+    Result
+};
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant}
+};
+
+/// How long to wait for the rest of a sequence that was cut off
+const CUT_OFF_TIME: Duration = Duration::from_millis(50);
+
+/// Ctrl+B, like tmux
+pub const DEFAULT_PREFIX: Key = Key::new(KeyCode::Char('b'), MOD_CTRL);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Spawn,
+    Close,
+    FocusNext,
+    FocusPrev,
+    Move(i8, i8),
+    Resize(i8, i8),
+    Raise,
+    NextLayout,
+    Detach,
+    Quit
 }
-impl Default for State {
-    fn default() -> Self {
-        State::Normal
+
+impl Command {
+    /// Look up a command by the name used in the config file
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "spawn" => Command::Spawn,
+            "close" => Command::Close,
+            "focus-next" => Command::FocusNext,
+            "focus-prev" => Command::FocusPrev,
+            "move-left" => Command::Move(-1, 0),
+            "move-right" => Command::Move(1, 0),
+            "move-up" => Command::Move(0, -1),
+            "move-down" => Command::Move(0, 1),
+            "resize-left" => Command::Resize(-1, 0),
+            "resize-right" => Command::Resize(1, 0),
+            "resize-up" => Command::Resize(0, -1),
+            "resize-down" => Command::Resize(0, 1),
+            "raise" => Command::Raise,
+            "next-layout" => Command::NextLayout,
+            "detach" => Command::Detach,
+            "quit" => Command::Quit,
+            _ => return None
+        })
     }
 }
 
+/// What a key does when pressed after the prefix
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Command(Command),
+    /// Toggle whether the arrow keys move or resize
+    ToggleResize
+}
+impl Action {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "toggle-resize" => Some(Action::ToggleResize),
+            _ => Command::from_name(name).map(Action::Command)
+        }
+    }
+}
+
+/// The keys that are bound when the config doesn't say otherwise
+pub fn default_bindings() -> HashMap<Key, Action> {
+    let char = |c| Key::new(KeyCode::Char(c), 0);
+
+    let mut bindings = HashMap::new();
+    bindings.insert(char('c'), Action::Command(Command::Spawn));
+    bindings.insert(char('x'), Action::Command(Command::Close));
+    bindings.insert(char('n'), Action::Command(Command::FocusNext));
+    bindings.insert(Key::new(KeyCode::Tab, 0), Action::Command(Command::FocusNext));
+    bindings.insert(char('p'), Action::Command(Command::FocusPrev));
+    bindings.insert(char('r'), Action::Command(Command::Raise));
+    bindings.insert(char(' '), Action::Command(Command::NextLayout));
+    bindings.insert(char('d'), Action::Command(Command::Detach));
+    bindings.insert(char('q'), Action::Command(Command::Quit));
+    bindings.insert(char('s'), Action::ToggleResize);
+    bindings
+}
+
 #[derive(Debug)]
-pub enum Event {
-    Unsupported(Vec<u8>),
-    Mouse(u8, u8, u8)
+pub enum Event<'a> {
+    /// Bytes to send to the focused window as they are
+    Input(&'a [u8]),
+    Mouse(Mouse),
+    Command(Command)
+}
+
+/// Returns which way an arrow key without modifiers points
+fn arrow(key: Key) -> Option<(i8, i8)> {
+    match key {
+        Key { code: KeyCode::Up, mods: 0 } => Some((0, -1)),
+        Key { code: KeyCode::Down, mods: 0 } => Some((0, 1)),
+        Key { code: KeyCode::Right, mods: 0 } => Some((1, 0)),
+        Key { code: KeyCode::Left, mods: 0 } => Some((-1, 0)),
+        _ => None
+    }
 }
 
-#[derive(Default)]
+/// Turns tokens into events, taking care of the prefix key and the command
+/// mode after it
 pub struct Parser {
-    state: State,
-    arg1: Option<u8>,
-    arg2: Option<u8>,
-    arg3: Option<u8>
+    tokenizer: Tokenizer,
+    command: bool,
+    /// Until when the arrow keys keep moving or resizing without the prefix
+    repeat: Option<Instant>,
+    /// Until when the rest of a sequence that was cut off is waited for
+    cut_off: Option<Instant>,
+
+    prefix: Key,
+    bindings: HashMap<Key, Action>,
+    repeat_time: Duration,
+    resizing: bool
 }
 impl Parser {
-    pub fn new() -> Self {
-        Self::default()
+    pub fn new(prefix: Key, bindings: HashMap<Key, Action>, repeat_time: Duration) -> Self {
+        Self {
+            tokenizer: Tokenizer::default(),
+            command: false,
+            repeat: None,
+            cut_off: None,
+
+            prefix,
+            bindings,
+            repeat_time,
+            resizing: false
+        }
+    }
+    /// Returns what the keys do right now, to show to the user, or None if
+    /// they go to the focused window
+    pub fn mode(&self) -> Option<&'static str> {
+        if self.command {
+            Some("prefix")
+        } else if self.repeat.is_none() {
+            None
+        } else if self.resizing {
+            Some("resize")
+        } else {
+            Some("move")
+        }
+    }
+    /// Returns when the arrow keys stop repeating, if they do
+    pub fn deadline(&self) -> Option<Instant> {
+        self.repeat
     }
-    /// Returns true if the byte should be sent like normal
-    pub fn feed<F>(&mut self, byte: u8, mut performer: F) -> Result<bool>
+    /// Returns when a sequence that was cut off should be given up on with
+    /// `flush`, if there is one
+    pub fn cut_off(&self) -> Option<Instant> {
+        self.cut_off
+    }
+    /// Stop repeating the arrow keys if the time is up. Returns true if
+    /// that changed the mode.
+    pub fn expire(&mut self, now: Instant) -> bool {
+        let expired = self.repeat.is_some_and(|deadline| now >= deadline);
+        if expired {
+            self.repeat = None;
+        }
+        expired
+    }
+    pub fn feed<F>(&mut self, data: &[u8], performer: F) -> Result<()>
         where F: FnMut(Event) -> Result<()>
     {
-        let was_normal = self.state == State::Normal;
-        match self.state {
-            State::Normal => if byte == b'\x1b' {
-                self.state = State::ESC;
-            },
-            State::ESC => if byte == b'[' {
-                self.state = State::CSI;
-            } else {
-                self.state = State::Normal;
-                performer(Event::Unsupported(vec![b'\x1b', byte]))?;
-            },
-            State::CSI => if byte == b'M' {
-                self.state = State::Mouse;
-            } else {
-                self.state = State::Normal;
-                performer(Event::Unsupported(vec![b'\x1b', b'[', byte]))?;
-            },
-            State::Mouse => if self.arg1.is_none() {
-                self.arg1 = Some(byte);
-            } else if self.arg2.is_none() {
-                self.arg2 = Some(byte);
+        let now = Instant::now();
+        self.expire(now);
+        self.handle(Some(data), performer)?;
+        self.cut_off = if self.tokenizer.is_pending() {
+            Some(self.cut_off.unwrap_or(now + CUT_OFF_TIME))
+        } else {
+            None
+        };
+        Ok(())
+    }
+    /// Give up on the rest of a sequence that was cut off, and handle what
+    /// there is of it
+    pub fn flush<F>(&mut self, performer: F) -> Result<()>
+        where F: FnMut(Event) -> Result<()>
+    {
+        self.cut_off = None;
+        self.handle(None, performer)
+    }
+    /// Feed `data` to the tokenizer, or flush it if there is none
+    fn handle<F>(&mut self, data: Option<&[u8]>, mut performer: F) -> Result<()>
+        where F: FnMut(Event) -> Result<()>
+    {
+        let Self { tokenizer, command, repeat, cut_off: _, prefix, bindings, repeat_time, resizing } = self;
+
+        // Collect input so a paste doesn't turn into one write per key
+        let mut input = Vec::new();
+        let handle = |token, raw: &[u8]| {
+            let arrow = match token {
+                Token::Key(key) if !bindings.contains_key(&key) => arrow(key),
+                _ => None
+            };
+            let move_or_resize = |(x, y)| Event::Command(if *resizing {
+                Command::Resize(x, y)
             } else {
-                self.arg3 = Some(byte);
-
-                performer(Event::Mouse(
-                    self.arg1.unwrap(),
-                    self.arg2.unwrap(),
-                    self.arg3.unwrap()
-                ))?;
-                self.arg1 = None;
-                self.arg2 = None;
-                self.arg3 = None;
-                self.state = State::Normal;
+                Command::Move(x, y)
+            });
+
+            // Arrow keys keep going for a while, anything else ends that and
+            // is handled as if the prefix was never pressed
+            if repeat.take().is_some() {
+                if let Some(arrow) = arrow {
+                    *repeat = Some(Instant::now() + *repeat_time);
+                    return performer(move_or_resize(arrow));
+                }
+            }
+
+            if !*command {
+                match token {
+                    Token::Key(key) if key == *prefix => {
+                        *command = true;
+                        *resizing = false;
+                    },
+                    Token::Mouse(mouse) => {
+                        if !input.is_empty() {
+                            performer(Event::Input(&input))?;
+                            input.clear();
+                        }
+                        performer(Event::Mouse(mouse))?;
+                    },
+                    _ => input.extend_from_slice(raw)
+                }
+                return Ok(());
+            }
+
+            if !input.is_empty() {
+                performer(Event::Input(&input))?;
+                input.clear();
             }
+            *command = false;
+            let key = match token {
+                Token::Key(key) => key,
+                _ => return Ok(())
+            };
+            if key == *prefix {
+                // Pressing the prefix twice sends it to the window
+                return performer(Event::Input(raw));
+            }
+
+            match (bindings.get(&key), arrow) {
+                (Some(&Action::Command(cmd)), _) => performer(Event::Command(cmd)),
+                (Some(Action::ToggleResize), _) => {
+                    *resizing = !*resizing;
+                    *repeat = Some(Instant::now() + *repeat_time);
+                    Ok(())
+                },
+                (None, Some(arrow)) => {
+                    *repeat = Some(Instant::now() + *repeat_time);
+                    performer(move_or_resize(arrow))
+                },
+                (None, None) => Ok(())
+            }
+        };
+        match data {
+            Some(data) => tokenizer.feed(data, handle)?,
+            None => tokenizer.flush(handle)?
+        }
+
+        if !input.is_empty() {
+            performer(Event::Input(&input))?;
         }
-        Ok(was_normal && self.state == State::Normal)
+        Ok(())
     }
 }
@@ -0,0 +1,581 @@
+use super::Result;
+
+use std::{borrow::Cow, mem, str};
+
+pub const MOD_SHIFT: u8 = 1;
+pub const MOD_ALT:   u8 = 1 << 1;
+pub const MOD_CTRL:  u8 = 1 << 2;
+pub const MOD_SUPER: u8 = 1 << 3;
+
+/// Give up on a control sequence that hasn't ended after this many bytes
+const MAX_SEQUENCE: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeyCode {
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    Escape,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Insert,
+    Delete,
+    PageUp,
+    PageDown,
+    F(u8)
+}
+
+/// A key press. Shifted letters are uppercase chars without `MOD_SHIFT`,
+/// however the terminal sent them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub code: KeyCode,
+    pub mods: u8
+}
+impl Key {
+    pub const fn new(code: KeyCode, mods: u8) -> Self {
+        Self { code, mods }
+    }
+    fn normalize(mut self) -> Self {
+        if let KeyCode::Char(c) = self.code {
+            if self.mods & MOD_SHIFT == MOD_SHIFT && c.is_ascii_alphabetic() {
+                self.code = KeyCode::Char(c.to_ascii_uppercase());
+                self.mods &= !MOD_SHIFT;
+            }
+        }
+        self
+    }
+    /// Parse a key like `"c"`, `"C-b"`, `"M-S-Left"`, `"Space"` or `"F5"`.
+    /// `C-` is ctrl, `M-` is alt, `S-` is shift and `s-` is super.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let mut mods = 0;
+        let mut name = name;
+        while name.len() > 2 {
+            let bit = match &name[..2] {
+                "C-" => MOD_CTRL,
+                "M-" => MOD_ALT,
+                "S-" => MOD_SHIFT,
+                "s-" => MOD_SUPER,
+                _ => break
+            };
+            mods |= bit;
+            name = &name[2..];
+        }
+
+        let code = match name {
+            "Enter" => KeyCode::Enter,
+            "Tab" => KeyCode::Tab,
+            "Backspace" => KeyCode::Backspace,
+            "Escape" | "Esc" => KeyCode::Escape,
+            "Space" => KeyCode::Char(' '),
+            "Up" => KeyCode::Up,
+            "Down" => KeyCode::Down,
+            "Left" => KeyCode::Left,
+            "Right" => KeyCode::Right,
+            "Home" => KeyCode::Home,
+            "End" => KeyCode::End,
+            "Insert" => KeyCode::Insert,
+            "Delete" => KeyCode::Delete,
+            "PageUp" => KeyCode::PageUp,
+            "PageDown" => KeyCode::PageDown,
+            _ => {
+                let mut chars = name.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if mods & MOD_CTRL == MOD_CTRL => KeyCode::Char(c.to_ascii_lowercase()),
+                    (Some(c), None) => KeyCode::Char(c),
+                    _ => match name.strip_prefix('F').and_then(|n| n.parse().ok()) {
+                        Some(n @ 1..=24) => KeyCode::F(n),
+                        _ => return None
+                    }
+                }
+            }
+        };
+        Some(Key::new(code, mods).normalize())
+    }
+}
+
+/// The ways a mouse report can be encoded, see `Mouse::encode`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MouseEncoding {
+    /// `ESC [ M` and three bytes offset by 32
+    X10,
+    /// Like X10, but coordinates are UTF-8 encoded (1005)
+    Utf8,
+    /// `ESC [ < b ; x ; y M`, or `m` for a release (1006)
+    Sgr,
+    /// `ESC [ b ; x ; y M` with b offset by 32 (1015)
+    Urxvt
+}
+
+/// A mouse report, however the host terminal encoded it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mouse {
+    /// The button and modifier bits, like in an X10 report but without the
+    /// offset of 32. X10 reports can't tell which button was released.
+    pub button: u8,
+    /// 0-based
+    pub x: u16,
+    pub y: u16,
+    pub release: bool
+}
+impl Mouse {
+    fn x10(button: u8, x: u8, y: u8) -> Self {
+        let button = button.wrapping_sub(32);
+        Self {
+            button,
+            x: x.saturating_sub(33) as u16,
+            y: y.saturating_sub(33) as u16,
+            release: button & 0b11 == 3 && button & (32 | 64) == 0
+        }
+    }
+    /// Returns true if the pointer moved, which is a drag if a button is
+    /// held
+    pub fn drag(&self) -> bool {
+        self.button & 32 == 32
+    }
+    /// Returns true if this is the scroll wheel rather than a button
+    pub fn wheel(&self) -> bool {
+        self.button & 64 == 64
+    }
+    /// Returns true if this is a button being pressed
+    pub fn press(&self) -> bool {
+        !self.drag() && !self.release
+    }
+    /// Encode the report at `x` and `y` instead, which are 1-based
+    pub fn encode(&self, encoding: MouseEncoding, x: u16, y: u16) -> Vec<u8> {
+        // Only SGR knows which button was released
+        let legacy = if self.release { (self.button & !0b11) | 3 } else { self.button };
+
+        match encoding {
+            MouseEncoding::X10 => {
+                let clamp = |n: u16| (n.min(255 - 32) + 32) as u8;
+                vec![b'\x1b', b'[', b'M', legacy.wrapping_add(32), clamp(x), clamp(y)]
+            },
+            MouseEncoding::Utf8 => {
+                let mut buf = vec![b'\x1b', b'[', b'M', legacy.wrapping_add(32)];
+                for n in [x, y] {
+                    let c = char::from_u32(n.min(2047 - 32) as u32 + 32).unwrap_or(' ');
+                    buf.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                buf
+            },
+            MouseEncoding::Sgr => format!(
+                "\x1b[<{};{};{}{}",
+                self.button, x, y,
+                if self.release { 'm' } else { 'M' }
+            ).into_bytes(),
+            MouseEncoding::Urxvt => format!("\x1b[{};{};{}M", legacy as u16 + 32, x, y).into_bytes()
+        }
+    }
+}
+
+/// One piece of input from the host terminal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Token {
+    Key(Key),
+    Mouse(Mouse),
+    /// Anything else, like focus reports and keys we don't know
+    Other
+}
+
+/// Splits input into tokens without losing any bytes, so that everything
+/// that isn't handled can be passed on as it was
+#[derive(Default)]
+pub struct Tokenizer {
+    pending: Vec<u8>
+}
+impl Tokenizer {
+    /// Call `f` with each token and the bytes it was made of. A sequence that
+    /// is cut off is kept until the next call or `flush`, except for a lone
+    /// escape at the end, which is the escape key.
+    pub fn feed<F>(&mut self, data: &[u8], mut f: F) -> Result<()>
+        where F: FnMut(Token, &[u8]) -> Result<()>
+    {
+        let buf = if self.pending.is_empty() {
+            Cow::Borrowed(data)
+        } else {
+            let mut buf = mem::take(&mut self.pending);
+            buf.extend_from_slice(data);
+            Cow::Owned(buf)
+        };
+
+        let mut i = 0;
+        while i < buf.len() {
+            match parse(&buf[i..]) {
+                Some((token, len)) => {
+                    f(token, &buf[i..i+len])?;
+                    i += len;
+                },
+                None => {
+                    self.pending = buf[i..].to_vec();
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Returns true if a sequence was cut off and is waiting for the rest
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+    /// Give up on the rest of a sequence that was cut off, and call `f` with
+    /// what there is of it
+    pub fn flush<F>(&mut self, mut f: F) -> Result<()>
+        where F: FnMut(Token, &[u8]) -> Result<()>
+    {
+        let pending = mem::take(&mut self.pending);
+        let token = match *pending {
+            [] => return Ok(()),
+            // Nothing came after all, so this was alt+[ or alt+O
+            [b'\x1b', byte @ (b'[' | b'O')] => Token::Key(Key::new(KeyCode::Char(byte as char), MOD_ALT)),
+            _ => Token::Other
+        };
+        f(token, &pending)
+    }
+}
+
+fn key(code: KeyCode) -> Token {
+    Token::Key(Key::new(code, 0))
+}
+
+/// Parse the token at the start of `buf`, returning it and its length, or
+/// `None` if it is cut off
+fn parse(buf: &[u8]) -> Option<(Token, usize)> {
+    match buf[0] {
+        b'\x1b' => match buf.get(1) {
+            None => Some((key(KeyCode::Escape), 1)),
+            // Could also be alt+[ or alt+O, which only `Tokenizer::flush`
+            // can tell
+            Some(b'[') | Some(b'O') if buf.len() == 2 => None,
+            Some(b'[') => csi(buf),
+            Some(b'O') => Some((ss3(buf[2]).map(key).unwrap_or(Token::Other), 3)),
+            Some(_) => match parse(&buf[1..])? {
+                // Escape in front of a key means alt is held
+                (Token::Key(mut key), len) => {
+                    key.mods |= MOD_ALT;
+                    Some((Token::Key(key.normalize()), 1 + len))
+                },
+                _ => Some((key(KeyCode::Escape), 1))
+            }
+        },
+        byte if byte < 0x80 => Some((Token::Key(c0(byte)), 1)),
+        byte => {
+            let len = match byte {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => return Some((Token::Other, 1))
+            };
+            if buf.len() < len {
+                return None;
+            }
+            match str::from_utf8(&buf[..len]).ok().and_then(|s| s.chars().next()) {
+                Some(c) => Some((key(KeyCode::Char(c)), len)),
+                None => Some((Token::Other, 1))
+            }
+        }
+    }
+}
+
+/// Returns the key an ASCII byte is sent by
+fn c0(byte: u8) -> Key {
+    match byte {
+        b'\r' => Key::new(KeyCode::Enter, 0),
+        b'\t' => Key::new(KeyCode::Tab, 0),
+        b'\x7f' => Key::new(KeyCode::Backspace, 0),
+        b'\x1b' => Key::new(KeyCode::Escape, 0),
+        0 => Key::new(KeyCode::Char(' '), MOD_CTRL),
+        0x01..=0x1a => Key::new(KeyCode::Char((byte - 1 + b'a') as char), MOD_CTRL),
+        0x1c..=0x1f => Key::new(KeyCode::Char((byte - 0x1c + b'\\') as char), MOD_CTRL),
+        _ => Key::new(KeyCode::Char(byte as char), 0)
+    }
+}
+
+/// Returns the key for the final byte of `ESC O x`
+fn ss3(byte: u8) -> Option<KeyCode> {
+    Some(match byte {
+        b'A' => KeyCode::Up,
+        b'B' => KeyCode::Down,
+        b'C' => KeyCode::Right,
+        b'D' => KeyCode::Left,
+        b'H' => KeyCode::Home,
+        b'F' => KeyCode::End,
+        b'P'..=b'S' => KeyCode::F(byte - b'P' + 1),
+        _ => return None
+    })
+}
+
+/// Parse `ESC [`, followed by parameter bytes, intermediate bytes and a final
+/// byte
+fn csi(buf: &[u8]) -> Option<(Token, usize)> {
+    if buf.get(2) == Some(&b'M') {
+        return if buf.len() >= 6 {
+            Some((Token::Mouse(Mouse::x10(buf[3], buf[4], buf[5])), 6))
+        } else {
+            None
+        };
+    }
+
+    let mut end = 2;
+    while buf.get(end).is_some_and(|b| (0x30..=0x3f).contains(b)) {
+        end += 1;
+    }
+    let params = &buf[2..end];
+    while buf.get(end).is_some_and(|b| (0x20..=0x2f).contains(b)) {
+        end += 1;
+    }
+    let intermediates = &buf[2 + params.len()..end];
+
+    match buf.get(end) {
+        None if end >= MAX_SEQUENCE => Some((Token::Other, end)),
+        None => None,
+        // Not a valid sequence, leave the offending byte for the next token
+        Some(b) if !(0x40..=0x7e).contains(b) => Some((Token::Other, end)),
+        Some(&b) => {
+            let token = match (params.first(), intermediates, b) {
+                (Some(b'<'), [], b'M') | (Some(b'<'), [], b'm') => sgr_mouse(&params[1..], b == b'm'),
+                (_, [], _) => csi_key(params, b).map(Token::Key),
+                _ => None
+            };
+            let token = token.unwrap_or(Token::Other);
+            Some((token, end + 1))
+        }
+    }
+}
+
+/// Split parameters like `1;5` or `97:65;2` into numbers, missing ones being
+/// `None`
+fn parse_params(params: &[u8]) -> Option<Vec<Vec<Option<u32>>>> {
+    str::from_utf8(params).ok()?
+        .split(';')
+        .map(|param| param.split(':')
+            .map(|n| if n.is_empty() { Ok(None) } else { n.parse().map(Some) })
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .ok())
+        .collect()
+}
+
+/// Parse the `b;x;y` of an SGR mouse report
+fn sgr_mouse(params: &[u8], release: bool) -> Option<Token> {
+    let params = parse_params(params)?;
+    let param = |i: usize| params.get(i).and_then(|p| p.first().copied().flatten());
+    Some(Token::Mouse(Mouse {
+        button: param(0)? as u8,
+        x: (param(1)?.saturating_sub(1)).min(u16::MAX as u32) as u16,
+        y: (param(2)?.saturating_sub(1)).min(u16::MAX as u32) as u16,
+        release
+    }))
+}
+
+/// Returns the modifiers in a `1 + bits` parameter
+fn modifiers(param: Option<u32>) -> u8 {
+    (param.unwrap_or(1).saturating_sub(1) & 0b1111) as u8
+}
+
+fn csi_key(params: &[u8], action: u8) -> Option<Key> {
+    // Private parameters like `<` and `?` aren't keys
+    if params.first().is_some_and(|b| (0x3c..=0x3f).contains(b)) {
+        return None;
+    }
+    let params = parse_params(params)?;
+    let param = |i: usize, sub: usize| params.get(i).and_then(|p| p.get(sub).copied().flatten());
+    let mods = modifiers(param(1, 0));
+
+    let code = match action {
+        b'A'..=b'D' | b'H' | b'F' | b'P'..=b'S' => ss3(action)?,
+        b'Z' => return Some(Key::new(KeyCode::Tab, MOD_SHIFT)),
+        b'~' => match param(0, 0)? {
+            // xterm's modifyOtherKeys
+            27 => return unicode(param(2, 0)?, modifiers(param(1, 0))),
+            1 | 7 => KeyCode::Home,
+            2 => KeyCode::Insert,
+            3 => KeyCode::Delete,
+            4 | 8 => KeyCode::End,
+            5 => KeyCode::PageUp,
+            6 => KeyCode::PageDown,
+            n @ 11..=15 => KeyCode::F(n as u8 - 10),
+            n @ 17..=21 => KeyCode::F(n as u8 - 11),
+            n @ 23..=26 => KeyCode::F(n as u8 - 12),
+            n @ 28..=29 => KeyCode::F(n as u8 - 13),
+            n @ 31..=34 => KeyCode::F(n as u8 - 14),
+            _ => return None
+        },
+        // The kitty keyboard protocol. Only presses and repeats are keys.
+        b'u' => return match param(1, 1) {
+            Some(3) => None,
+            _ => unicode(param(0, 0)?, mods)
+        },
+        _ => return None
+    };
+    Some(Key::new(code, mods))
+}
+
+/// Returns the key for a unicode codepoint, as sent by the kitty protocol
+/// and modifyOtherKeys
+fn unicode(code: u32, mods: u8) -> Option<Key> {
+    let code = match code {
+        9 => KeyCode::Tab,
+        13 => KeyCode::Enter,
+        27 => KeyCode::Escape,
+        127 => KeyCode::Backspace,
+        // Kitty puts keys without a codepoint in the private use area
+        0xe000..=0xf8ff => return None,
+        _ => KeyCode::Char(char::from_u32(code)?)
+    };
+    Some(Key::new(code, mods).normalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns every token in `chunks` fed one after another, along with
+    /// the bytes it was made of
+    fn tokens(chunks: &[&[u8]]) -> Vec<(Token, Vec<u8>)> {
+        let mut tokenizer = Tokenizer::default();
+        let mut tokens = Vec::new();
+        for chunk in chunks {
+            tokenizer.feed(chunk, |token, raw| {
+                tokens.push((token, raw.to_vec()));
+                Ok(())
+            }).unwrap();
+        }
+        tokens
+    }
+    /// Returns the only token in `input`
+    fn token(input: &[u8]) -> Token {
+        let tokens = tokens(&[input]);
+        assert_eq!(tokens.len(), 1, "{:?} is {:?}", input, tokens);
+        assert_eq!(tokens[0].1, input);
+        tokens[0].0
+    }
+    fn key(code: KeyCode, mods: u8) -> Token {
+        Token::Key(Key::new(code, mods))
+    }
+
+    #[test]
+    fn csi_modifiers() {
+        assert_eq!(token(b"\x1b[A"), key(KeyCode::Up, 0));
+        assert_eq!(token(b"\x1b[1;5A"), key(KeyCode::Up, MOD_CTRL));
+        assert_eq!(token(b"\x1b[1;4D"), key(KeyCode::Left, MOD_SHIFT | MOD_ALT));
+        assert_eq!(token(b"\x1b[3;2~"), key(KeyCode::Delete, MOD_SHIFT));
+        assert_eq!(token(b"\x1b[15;3~"), key(KeyCode::F(5), MOD_ALT));
+        assert_eq!(token(b"\x1b[24~"), key(KeyCode::F(12), 0));
+        assert_eq!(token(b"\x1b[Z"), key(KeyCode::Tab, MOD_SHIFT));
+        // Focus reports and the like aren't keys
+        assert_eq!(token(b"\x1b[I"), Token::Other);
+        assert_eq!(token(b"\x1b[?1;2c"), Token::Other);
+    }
+    #[test]
+    fn ss3() {
+        assert_eq!(token(b"\x1bOP"), key(KeyCode::F(1), 0));
+        assert_eq!(token(b"\x1bOQ"), key(KeyCode::F(2), 0));
+        assert_eq!(token(b"\x1bOR"), key(KeyCode::F(3), 0));
+        assert_eq!(token(b"\x1bOS"), key(KeyCode::F(4), 0));
+        assert_eq!(token(b"\x1bOA"), key(KeyCode::Up, 0));
+        assert_eq!(token(b"\x1bOx"), Token::Other);
+    }
+    #[test]
+    fn kitty() {
+        assert_eq!(token(b"\x1b[97u"), key(KeyCode::Char('a'), 0));
+        assert_eq!(token(b"\x1b[97;5u"), key(KeyCode::Char('a'), MOD_CTRL));
+        assert_eq!(token(b"\x1b[97;2u"), key(KeyCode::Char('A'), 0));
+        assert_eq!(token(b"\x1b[13;3u"), key(KeyCode::Enter, MOD_ALT));
+        assert_eq!(token(b"\x1b[97;5:2u"), key(KeyCode::Char('a'), MOD_CTRL));
+        // Releases, and keys without a codepoint
+        assert_eq!(token(b"\x1b[97;5:3u"), Token::Other);
+        assert_eq!(token(b"\x1b[57441u"), Token::Other);
+    }
+    #[test]
+    fn modify_other_keys() {
+        assert_eq!(token(b"\x1b[27;5;105~"), key(KeyCode::Char('i'), MOD_CTRL));
+        assert_eq!(token(b"\x1b[27;6;73~"), key(KeyCode::Char('I'), MOD_CTRL));
+        assert_eq!(token(b"\x1b[27;5;13~"), key(KeyCode::Enter, MOD_CTRL));
+    }
+    #[test]
+    fn c0() {
+        assert_eq!(token(b"\x02"), key(KeyCode::Char('b'), MOD_CTRL));
+        assert_eq!(token(b"\x00"), key(KeyCode::Char(' '), MOD_CTRL));
+        assert_eq!(token(b"\x1c"), key(KeyCode::Char('\\'), MOD_CTRL));
+        assert_eq!(token(b"\x1f"), key(KeyCode::Char('_'), MOD_CTRL));
+        assert_eq!(token(b"\r"), key(KeyCode::Enter, 0));
+        assert_eq!(token(b"\t"), key(KeyCode::Tab, 0));
+        assert_eq!(token(b"\x7f"), key(KeyCode::Backspace, 0));
+        assert_eq!(token(b"\x1b"), key(KeyCode::Escape, 0));
+        assert_eq!(token(b"a"), key(KeyCode::Char('a'), 0));
+        // Escape in front means alt
+        assert_eq!(token(b"\x1bx"), key(KeyCode::Char('x'), MOD_ALT));
+        assert_eq!(token(b"\x1b\x02"), key(KeyCode::Char('b'), MOD_CTRL | MOD_ALT));
+    }
+    #[test]
+    fn utf8() {
+        assert_eq!(token("é".as_bytes()), key(KeyCode::Char('é'), 0));
+        assert_eq!(token("\u{1f600}".as_bytes()), key(KeyCode::Char('\u{1f600}'), 0));
+        // Invalid bytes are skipped one at a time, without losing any
+        assert_eq!(tokens(&[b"\xff\xc3("]), vec![
+            (Token::Other, b"\xff".to_vec()),
+            (Token::Other, b"\xc3".to_vec()),
+            (key(KeyCode::Char('('), 0), b"(".to_vec())
+        ]);
+    }
+    #[test]
+    fn split() {
+        assert_eq!(tokens(&[b"x\x1b[1;", b"5A"]), vec![
+            (key(KeyCode::Char('x'), 0), b"x".to_vec()),
+            (key(KeyCode::Up, MOD_CTRL), b"\x1b[1;5A".to_vec())
+        ]);
+        assert_eq!(tokens(&[b"\xe2\x82", b"\xac"]), vec![
+            (key(KeyCode::Char('€'), 0), "€".as_bytes().to_vec())
+        ]);
+        assert_eq!(tokens(&[b"\x1b[<0;1", b"0;20M"]), vec![
+            (Token::Mouse(Mouse { button: 0, x: 9, y: 19, release: false }), b"\x1b[<0;10;20M".to_vec())
+        ]);
+        assert_eq!(tokens(&[b"\x1b[", b"<0;1;1M"]), vec![
+            (Token::Mouse(Mouse { button: 0, x: 0, y: 0, release: false }), b"\x1b[<0;1;1M".to_vec())
+        ]);
+        assert_eq!(tokens(&[b"\x1bO", b"A"]), vec![
+            (key(KeyCode::Up, 0), b"\x1bOA".to_vec())
+        ]);
+        // A sequence that never ends is given up on
+        let mut long = b"\x1b[".to_vec();
+        long.extend_from_slice(&[b'1'; MAX_SEQUENCE]);
+        assert_eq!(tokens(&[&long])[0].0, Token::Other);
+    }
+    #[test]
+    fn flush() {
+        let flushed = |input: &[u8]| {
+            let mut tokenizer = Tokenizer::default();
+            let mut tokens = Vec::new();
+            let mut push = |token, raw: &[u8]| {
+                tokens.push((token, raw.to_vec()));
+                Ok(())
+            };
+            tokenizer.feed(input, &mut push).unwrap();
+            assert!(tokenizer.is_pending());
+            tokenizer.flush(&mut push).unwrap();
+            assert!(!tokenizer.is_pending());
+            tokens
+        };
+        assert_eq!(flushed(b"\x1b["), vec![(key(KeyCode::Char('['), MOD_ALT), b"\x1b[".to_vec())]);
+        assert_eq!(flushed(b"\x1bO"), vec![(key(KeyCode::Char('O'), MOD_ALT), b"\x1bO".to_vec())]);
+        assert_eq!(flushed(b"x\x1b[1;"), vec![
+            (key(KeyCode::Char('x'), 0), b"x".to_vec()),
+            (Token::Other, b"\x1b[1;".to_vec())
+        ]);
+    }
+    #[test]
+    fn mouse() {
+        assert_eq!(token(b"\x1b[M !!"), Token::Mouse(Mouse { button: 0, x: 0, y: 0, release: false }));
+        assert_eq!(token(b"\x1b[M#*+"), Token::Mouse(Mouse { button: 3, x: 9, y: 10, release: true }));
+        assert_eq!(token(b"\x1b[M`!!"), Token::Mouse(Mouse { button: 64, x: 0, y: 0, release: false }));
+
+        assert_eq!(token(b"\x1b[<0;10;20M"), Token::Mouse(Mouse { button: 0, x: 9, y: 19, release: false }));
+        assert_eq!(token(b"\x1b[<2;10;20m"), Token::Mouse(Mouse { button: 2, x: 9, y: 19, release: true }));
+        assert_eq!(token(b"\x1b[<32;1000;300M"), Token::Mouse(Mouse { button: 32, x: 999, y: 299, release: false }));
+    }
+}
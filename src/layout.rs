@@ -0,0 +1,108 @@
+/// The smallest frame that still has room for one cell of content
+const MIN_SIZE: u16 = 3;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16
+}
+impl Rect {
+    /// Returns the part that is inside of both, if any
+    pub fn intersection(self, other: Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let end_x = (self.x + self.width).min(other.x + other.width);
+        let end_y = (self.y + self.height).min(other.y + other.height);
+        if x >= end_x || y >= end_y {
+            return None;
+        }
+        Some(Rect { x, y, width: end_x - x, height: end_y - y })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// Windows stay wherever they are put
+    #[default]
+    Floating,
+    /// The top window takes the left half, the rest share the right half
+    MasterStack,
+    /// Windows are put in a grid of roughly square cells
+    Grid,
+    /// Windows are put side by side
+    Columns,
+    /// Every window covers the whole screen
+    Monocle
+}
+impl Layout {
+    pub fn next(self) -> Self {
+        match self {
+            Layout::Floating => Layout::MasterStack,
+            Layout::MasterStack => Layout::Grid,
+            Layout::Grid => Layout::Columns,
+            Layout::Columns => Layout::Monocle,
+            Layout::Monocle => Layout::Floating
+        }
+    }
+    /// Returns the frame of each of `count` windows on a screen of the
+    /// specified size, starting with the top window. Returns None if windows
+    /// should be left alone.
+    pub fn arrange(self, count: usize, width: u16, height: u16) -> Option<Vec<Rect>> {
+        let full = Rect { x: 0, y: 0, width, height };
+        let rects = match self {
+            Layout::Floating => return None,
+            _ if count == 0 => Vec::new(),
+            Layout::MasterStack if count == 1 => vec![full],
+            Layout::MasterStack => {
+                let (x, master_width) = split(width, 2, 0);
+                let mut rects = vec![Rect { x, y: 0, width: master_width, height }];
+
+                let (x, stack_width) = split(width, 2, 1);
+                for i in 0..count - 1 {
+                    let (y, height) = split(height, count - 1, i);
+                    rects.push(Rect { x, y, width: stack_width, height });
+                }
+                rects
+            },
+            Layout::Grid => {
+                let cols = (1..).find(|cols| cols * cols >= count).unwrap();
+                let rows = count.div_ceil(cols);
+
+                (0..count).map(|i| {
+                    let row = i / cols;
+                    // The last row stretches its windows to fill the gap
+                    let cols_in_row = if row + 1 == rows { count - row * cols } else { cols };
+
+                    let (x, width) = split(width, cols_in_row, i % cols);
+                    let (y, height) = split(height, rows, row);
+                    Rect { x, y, width, height }
+                }).collect()
+            },
+            Layout::Columns => (0..count).map(|i| {
+                let (x, width) = split(width, count, i);
+                Rect { x, y: 0, width, height }
+            }).collect(),
+            Layout::Monocle => vec![full; count]
+        };
+        Some(rects.into_iter()
+            .map(|rect| Rect {
+                width: rect.width.max(MIN_SIZE),
+                height: rect.height.max(MIN_SIZE),
+                ..rect
+            })
+            .collect())
+    }
+}
+
+/// Split `total` into `parts` and return the start and length of part `i`,
+/// spreading the remainder over the first parts
+fn split(total: u16, parts: usize, i: usize) -> (u16, u16) {
+    let parts = parts as u16;
+    let i = i as u16;
+    let base = total / parts;
+    let extra = total % parts;
+
+    (i * base + i.min(extra), base + if i < extra { 1 } else { 0 })
+}
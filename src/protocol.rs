@@ -0,0 +1,203 @@
+use super::color::ColorDepth;
+
+use std::{
+    env,
+    fs::{self, DirBuilder},
+    io::{self, prelude::*},
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt},
+        net::UnixStream
+    },
+    path::PathBuf
+};
+
+const KIND_ATTACH: u8 = 0;
+const KIND_DETACH: u8 = 1;
+const KIND_INPUT: u8 = 2;
+const KIND_RESIZE: u8 = 3;
+const KIND_OUTPUT: u8 = 4;
+const KIND_DETACHED: u8 = 5;
+const KIND_EXIT: u8 = 6;
+const KIND_DRAWN: u8 = 7;
+
+/// Kind + payload length
+const HEADER_LEN: usize = 1 + 4;
+
+/// A message between the client and the server. Each message is sent as a
+/// kind byte, a big endian u32 payload length and then the payload.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message {
+    /// Client -> server: Attach this client, replacing any previous one.
+    /// `term` is the terminfo name of the client's terminal.
+    Attach { cols: u16, rows: u16, colors: ColorDepth, term: String },
+    /// Client -> server: Detach the attached client
+    Detach,
+    /// Client -> server: Bytes typed by the user
+    Input(Vec<u8>),
+    /// Client -> server: The host terminal was resized
+    Resize { cols: u16, rows: u16 },
+    /// Client -> server: The last frame is all on the host terminal, and
+    /// the next one can be sent
+    Drawn,
+    /// Server -> client: Bytes to write to the host terminal
+    Output(Vec<u8>),
+    /// Server -> client: Another client took over, or detach was requested
+    Detached,
+    /// Server -> client: The server is shutting down
+    Exit
+}
+impl Message {
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let size;
+        let mut attach;
+        let (kind, payload): (u8, &[u8]) = match self {
+            Message::Attach { cols, rows, colors, term } => {
+                attach = encode_size(*cols, *rows).to_vec();
+                attach.push(colors.to_byte());
+                attach.extend_from_slice(term.as_bytes());
+                (KIND_ATTACH, &attach)
+            },
+            Message::Resize { cols, rows } => {
+                size = encode_size(*cols, *rows);
+                (KIND_RESIZE, &size)
+            },
+            Message::Detach => (KIND_DETACH, &[]),
+            Message::Drawn => (KIND_DRAWN, &[]),
+            Message::Input(data) => (KIND_INPUT, data),
+            Message::Output(data) => (KIND_OUTPUT, data),
+            Message::Detached => (KIND_DETACHED, &[]),
+            Message::Exit => (KIND_EXIT, &[])
+        };
+        write_message(w, kind, payload)
+    }
+    /// Returns the message as it is sent, for writing it where `write_to`
+    /// can't
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes).expect("writing to a Vec can't fail");
+        bytes
+    }
+}
+
+/// Writes the message in one go, so that a `DelayingWriter` that is about to
+/// be full takes all of it or none
+fn write_message<W: Write>(w: &mut W, kind: u8, payload: &[u8]) -> io::Result<()> {
+    let mut message = Vec::with_capacity(HEADER_LEN + payload.len());
+    message.push(kind);
+    message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    message.extend_from_slice(payload);
+    w.write_all(&message)
+}
+/// Same as writing `Message::Input`, for when the data is borrowed
+pub fn write_input<W: Write>(w: &mut W, data: &[u8]) -> io::Result<()> {
+    write_message(w, KIND_INPUT, data)
+}
+
+fn encode_size(cols: u16, rows: u16) -> [u8; 4] {
+    let (cols, rows) = (cols.to_be_bytes(), rows.to_be_bytes());
+    [cols[0], cols[1], rows[0], rows[1]]
+}
+fn decode_size(payload: &[u8]) -> io::Result<(u16, u16)> {
+    if payload.len() != 4 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid size payload"));
+    }
+    Ok((
+        u16::from_be_bytes([payload[0], payload[1]]),
+        u16::from_be_bytes([payload[2], payload[3]])
+    ))
+}
+
+/// Collects bytes from a non-blocking stream and splits them into messages
+#[derive(Default)]
+pub struct Reader {
+    buf: Vec<u8>
+}
+impl Reader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Read everything available. Returns false if the stream was closed.
+    pub fn read_from<R: Read>(&mut self, r: &mut R) -> io::Result<bool> {
+        let mut buf = [0; 4096];
+        loop {
+            match super::maybe(r.read(&mut buf))? {
+                Some(0) => return Ok(false),
+                Some(n) => self.buf.extend_from_slice(&buf[..n]),
+                None => return Ok(true)
+            }
+        }
+    }
+    /// Return the next complete message, if any
+    pub fn next(&mut self) -> io::Result<Option<Message>> {
+        if self.buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([self.buf[1], self.buf[2], self.buf[3], self.buf[4]]) as usize;
+        if self.buf.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let kind = self.buf[0];
+        let payload: Vec<u8> = self.buf.drain(..HEADER_LEN + len).skip(HEADER_LEN).collect();
+        Ok(Some(match kind {
+            KIND_ATTACH => {
+                let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid attach payload");
+                let colors = payload.get(4).and_then(|&byte| ColorDepth::from_byte(byte)).ok_or_else(invalid)?;
+                let term = String::from_utf8(payload[5..].to_vec()).map_err(|_| invalid())?;
+                let (cols, rows) = decode_size(&payload[..4])?;
+                Message::Attach { cols, rows, colors, term }
+            },
+            KIND_DETACH => Message::Detach,
+            KIND_INPUT => Message::Input(payload),
+            KIND_RESIZE => {
+                let (cols, rows) = decode_size(&payload)?;
+                Message::Resize { cols, rows }
+            },
+            KIND_DRAWN => Message::Drawn,
+            KIND_OUTPUT => Message::Output(payload),
+            KIND_DETACHED => Message::Detached,
+            KIND_EXIT => Message::Exit,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown message kind"))
+        }))
+    }
+}
+
+/// Returns the directory sockets are kept in, creating it if needed
+pub fn socket_dir() -> io::Result<PathBuf> {
+    let uid = unsafe { libc::getuid() };
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime) => PathBuf::from(runtime).join("termwm"),
+        None => env::temp_dir().join(format!("termwm-{}", uid))
+    };
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)?;
+
+    // Anyone could have made it first in /tmp, and then whoever attaches
+    // would be talking to their socket
+    let meta = fs::symlink_metadata(&dir)?;
+    if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o777 != 0o700 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a directory only you can access", dir.display())
+        ));
+    }
+    Ok(dir)
+}
+/// Returns the path of the session socket
+pub fn socket_path() -> io::Result<PathBuf> {
+    Ok(socket_dir()?.join("default"))
+}
+/// Returns the path of the control socket, see `control`
+pub fn control_path() -> io::Result<PathBuf> {
+    Ok(socket_dir()?.join("control"))
+}
+/// Connect to a running server
+pub fn connect() -> io::Result<UnixStream> {
+    let path = socket_path()?;
+    UnixStream::connect(&path).map_err(|err| io::Error::new(
+        err.kind(),
+        format!("no termwm server at {}: {}", path.display(), err)
+    ))
+}
@@ -0,0 +1,486 @@
+use super::{
+    config::Config,
+    control::Controls,
+    delaying::DelayingWriter,
+    input::{Parser, Event},
+    maybe,
+    protocol::{self, Message, Reader},
+    terminal::Terminal,
+    workspace::Workspace,
+    Result
+};
+
+use mio::{*, unix::EventedFd};
+use std::{
+    collections::{HashMap, VecDeque},
+    ffi::OsString,
+    fs,
+    io::{self, prelude::*},
+    num::NonZeroU32,
+    os::unix::{
+        io::AsRawFd,
+        net::{UnixListener, UnixStream}
+    },
+    path::PathBuf,
+    time::{Duration, Instant}
+};
+
+const TOKEN_LISTENER: Token = Token(0);
+const TOKEN_CONTROL: Token = Token(1);
+const TOKEN_PTY: Token = Token(2);
+/// Connections are numbered from here so they never collide with ptys
+const TOKEN_CONNECTION: Token = Token(usize::MAX / 2);
+/// Same for control connections, which never collide with the above either
+const TOKEN_CONTROL_CONNECTION: Token = Token(usize::MAX / 4 * 3);
+
+/// How much input is kept while the focused window isn't taking it, before
+/// the client isn't read anymore either
+const HELD_INPUT: usize = 64 * 1024;
+
+/// How much is read from a window at a time, so that one busy window can't
+/// hold up input and the other windows
+const READ_BUDGET: usize = 16 * 1024;
+
+/// Limits how fast a window's output is read, see
+/// `WindowConfig::throughput`. Up to a second's worth can be read at once.
+struct Throttle {
+    rate: u32,
+    allowance: f64,
+    updated: Instant
+}
+impl Throttle {
+    fn new(rate: NonZeroU32) -> Self {
+        let rate = rate.get();
+        Self {
+            rate,
+            allowance: rate as f64,
+            updated: Instant::now()
+        }
+    }
+    /// Returns how many bytes may be read now
+    fn available(&mut self) -> usize {
+        let now = Instant::now();
+        let earned = now.duration_since(self.updated).as_secs_f64() * self.rate as f64;
+        self.allowance = (self.allowance + earned).min(self.rate as f64);
+        self.updated = now;
+        self.allowance as usize
+    }
+    fn spend(&mut self, bytes: usize) {
+        self.allowance -= bytes as f64;
+    }
+    /// Returns how long until a bit more may be read
+    fn wait(&self, bytes: usize) -> Duration {
+        let bytes = bytes.min(self.rate as usize) as f64;
+        Duration::from_secs_f64((bytes - self.allowance).max(0.0) / self.rate as f64)
+    }
+}
+
+/// Returns whichever is sooner, `None` being never
+fn sooner(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b)
+    }
+}
+
+struct Connection {
+    stream: DelayingWriter<UnixStream>,
+    reader: Reader,
+    /// Whether a frame was sent that the client hasn't drawn yet
+    drawing: bool,
+    /// Input waiting for the focused window to take what it already got
+    held: VecDeque<Vec<u8>>,
+    /// Whether reading stopped with more to read, since too much input is
+    /// held already
+    stalled: bool
+}
+impl Connection {
+    fn held_len(&self) -> usize {
+        self.held.iter().map(Vec::len).sum()
+    }
+    /// Read everything available, unless too much input is held. Returns
+    /// false if the client hung up.
+    fn read(&mut self) -> bool {
+        if self.held_len() >= HELD_INPUT {
+            self.stalled = true;
+            return true;
+        }
+        self.stalled = false;
+        self.reader.read_from(&mut *self.stream).unwrap_or(false)
+    }
+}
+
+/// Keeps track of everyone connected to the socket, and which of them is the
+/// client currently showing the workspace
+struct Connections {
+    map: HashMap<Token, Connection>,
+    next: Token,
+    attached: Option<Token>
+}
+impl Connections {
+    fn accept(&mut self, poll: &Poll, listener: &UnixListener) -> Result<()> {
+        while let Some((stream, _)) = maybe(listener.accept())? {
+            stream.set_nonblocking(true)?;
+            poll.register(&EventedFd(&stream.as_raw_fd()), self.next, Ready::readable() | Ready::writable(), PollOpt::edge())?;
+
+            self.map.insert(self.next, Connection {
+                stream: DelayingWriter::new(stream),
+                reader: Reader::new(),
+                drawing: false,
+                held: VecDeque::new(),
+                stalled: false
+            });
+            self.next = Token(usize::from(self.next) + 1);
+        }
+        Ok(())
+    }
+    fn remove(&mut self, poll: &Poll, token: Token) {
+        if let Some(conn) = self.map.remove(&token) {
+            let _ = poll.deregister(&EventedFd(&conn.stream.as_raw_fd()));
+        }
+        if self.attached == Some(token) {
+            self.attached = None;
+        }
+    }
+    /// Send a message, dropping the connection if that fails
+    fn send(&mut self, poll: &Poll, token: Token, message: &Message) {
+        let failed = match self.map.get_mut(&token) {
+            // Frames are one at a time and the rest are small, so there is
+            // no need to hold any of them back
+            Some(conn) => conn.stream.push(&message.to_bytes())
+                .and_then(|()| conn.stream.flush())
+                .is_err(),
+            None => false
+        };
+        if failed {
+            self.remove(poll, token);
+        }
+    }
+    /// Tell the attached client to go away, if any
+    fn detach(&mut self, poll: &Poll) {
+        if let Some(token) = self.attached {
+            self.send(poll, token, &Message::Detached);
+            self.remove(poll, token);
+        }
+    }
+}
+
+/// Run the server. `shell` overrides the one in the config.
+pub fn run(shell: Option<OsString>) -> Result<()> {
+    let mut config = Config::load()?;
+    if let Some(shell) = shell {
+        config.shell = Some(PathBuf::from(shell));
+        config.args.clear();
+    }
+
+    let path = protocol::socket_path()?;
+    if UnixStream::connect(&path).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse, "a termwm server is already running").into());
+    }
+    // Nobody is listening, it's left over from a crash
+    let _ = fs::remove_file(&path);
+
+    let listener = UnixListener::bind(&path)?;
+    listener.set_nonblocking(true)?;
+
+    let control_path = protocol::control_path()?;
+    let _ = fs::remove_file(&control_path);
+    let control = UnixListener::bind(&control_path)?;
+    control.set_nonblocking(true)?;
+
+    let result = serve(&config, &listener, &control);
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(&control_path);
+    result
+}
+
+fn serve(config: &Config, listener: &UnixListener, control: &UnixListener) -> Result<()> {
+    // The real size is known once a client attaches
+    let mut workspace = Workspace::new(config, TOKEN_PTY, 80, 24)?;
+    workspace.poll.register(&EventedFd(&listener.as_raw_fd()), TOKEN_LISTENER, Ready::readable(), PollOpt::edge())?;
+    workspace.poll.register(&EventedFd(&control.as_raw_fd()), TOKEN_CONTROL, Ready::readable(), PollOpt::edge())?;
+
+    let mut connections = Connections {
+        map: HashMap::new(),
+        next: TOKEN_CONNECTION,
+        attached: None
+    };
+    let mut controls = Controls::new(TOKEN_CONTROL_CONNECTION);
+    let mut parser = Parser::new(
+        config.keys.prefix,
+        config.keys.bind.clone(),
+        Duration::from_millis(config.keys.repeat_time)
+    );
+
+    let mut buf = [0; 1024];
+    let mut events = Events::with_capacity(1024);
+    let mut timeout = None;
+    let interval = Duration::from_secs(1) / config.fps.get();
+    let mut last = Instant::now();
+    // Whether anything changed since the last frame
+    let mut dirty = true;
+    // Windows that have output waiting to be read, in the order they get to
+    let mut pending = VecDeque::new();
+    let mut throttles = HashMap::new();
+    // Connections that sent something, and whether they hung up after
+    let mut received = Vec::new();
+    'main: loop {
+        workspace.poll.poll(&mut events, timeout)?;
+
+        for event in events.iter() {
+            match event.token() {
+                TOKEN_LISTENER => connections.accept(&workspace.poll, listener)?,
+                TOKEN_CONTROL => controls.accept(&workspace.poll, control)?,
+                token if token >= TOKEN_CONTROL_CONNECTION => {
+                    dirty |= event.readiness().is_readable();
+                    controls.ready(&mut workspace, token, event.readiness());
+                },
+                token if token >= TOKEN_CONNECTION => {
+                    let mut closed = false;
+                    if let Some(conn) = connections.map.get_mut(&token) {
+                        if event.readiness().is_writable() && conn.stream.write_todo().is_err() {
+                            closed = true;
+                        }
+                        if event.readiness().is_readable() && !conn.read() {
+                            closed = true;
+                        }
+                    }
+                    received.push((token, closed));
+                },
+                token if token >= TOKEN_PTY => if let Some(window) = workspace.windows.get_mut(&token) {
+                    if event.readiness().is_writable()
+                            && window.pty.write_todo()? {
+                        window.pty.flush()?;
+                    }
+                    if event.readiness().is_readable() && !pending.contains(&token) {
+                        pending.push_back(token);
+                    }
+                },
+                _ => ()
+            }
+        }
+
+        // Input waits while the focused window isn't taking it, and once
+        // enough is waiting the client isn't read anymore, which makes it
+        // stop reading the terminal in turn. Nothing is dropped, and until
+        // then everything else the client sends is handled as usual.
+        loop {
+            for (token, mut closed) in received.drain(..) {
+                loop {
+                    let message = match connections.map.get_mut(&token).map(|conn| conn.reader.next()) {
+                        Some(Ok(Some(message))) => message,
+                        Some(Err(_)) => {
+                            closed = true;
+                            break;
+                        },
+                        _ => break
+                    };
+                    // Acks only say when to draw, not that there is anything new
+                    dirty |= message != Message::Drawn;
+                    match message {
+                        Message::Attach { cols, rows, colors, term } => {
+                            if connections.attached != Some(token) {
+                                connections.detach(&workspace.poll);
+                                connections.attached = Some(token);
+                            }
+                            workspace.buffer.set_terminal(Terminal::new(&term, colors, config.scroll_margins));
+                            // Also makes sure the next frame is drawn from scratch
+                            workspace.resize(cols.max(1), rows.max(1))?;
+                        },
+                        Message::Detach => {
+                            connections.detach(&workspace.poll);
+                            closed = true;
+                            break;
+                        },
+                        Message::Input(data) => if connections.attached == Some(token) {
+                            if let Some(conn) = connections.map.get_mut(&token) {
+                                conn.held.push_back(data);
+                            }
+                        },
+                        Message::Resize { cols, rows } => if connections.attached == Some(token) {
+                            workspace.resize(cols.max(1), rows.max(1))?;
+                        },
+                        Message::Drawn => if let Some(conn) = connections.map.get_mut(&token) {
+                            conn.drawing = false;
+                        },
+                        // Only servers send these
+                        Message::Output(_) | Message::Detached | Message::Exit => {
+                            closed = true;
+                            break;
+                        }
+                    }
+                }
+                if closed {
+                    connections.remove(&workspace.poll, token);
+                }
+            }
+
+            let token = match connections.attached {
+                Some(token) => token,
+                None => break
+            };
+            while let Some(conn) = connections.map.get_mut(&token) {
+                let data = if workspace.input_blocked() { None } else { conn.held.pop_front() };
+                // A sequence that was cut off is given up on once the time is
+                // up and no input that could finish it is waiting
+                let cut_off = conn.held.is_empty() && parser.cut_off().is_some_and(|deadline| Instant::now() >= deadline);
+                if data.is_none() && !cut_off {
+                    break;
+                }
+                dirty = true;
+                input(&mut workspace, &mut parser, data.as_deref())?;
+                if workspace.quit {
+                    break 'main;
+                }
+                if workspace.detach {
+                    workspace.detach = false;
+                    connections.detach(&workspace.poll);
+                }
+            }
+            // Pick up where a stalled client left off once there is room again
+            match connections.map.get_mut(&token) {
+                Some(conn) if conn.stalled && conn.held_len() < HELD_INPUT => {
+                    let closed = !conn.read();
+                    received.push((token, closed));
+                },
+                _ => break
+            }
+        }
+
+        // Read a budget's worth from each window with output waiting.
+        // Windows with more to read go to the back of the queue.
+        let mut wait = None;
+        for _ in 0..pending.len() {
+            let token = match pending.pop_front() {
+                Some(token) => token,
+                None => break
+            };
+            let window = match workspace.windows.get_mut(&token) {
+                Some(window) => window,
+                None => continue
+            };
+            let mut throttle = config.window.throughput.map(|rate| throttles.entry(token).or_insert_with(|| Throttle::new(rate)));
+
+            let mut budget = READ_BUDGET;
+            if let Some(ref mut throttle) = throttle {
+                budget = budget.min(throttle.available());
+                if budget == 0 {
+                    wait = sooner(wait, Some(throttle.wait(buf.len())));
+                    pending.push_back(token);
+                    continue;
+                }
+            }
+
+            let mut read = 0;
+            let mut closed = false;
+            let mut more = true;
+            while read < budget {
+                let len = buf.len().min(budget - read);
+                match maybe(window.pty.read(&mut buf[..len])) {
+                    Ok(Some(0)) | Err(_) => {
+                        closed = true;
+                        break;
+                    },
+                    Ok(None) => {
+                        more = false;
+                        break;
+                    },
+                    Ok(Some(n)) => {
+                        window.write(&buf[..n]);
+                        read += n;
+                    }
+                }
+            }
+            if let Some(throttle) = throttle {
+                throttle.spend(read);
+            }
+            dirty |= read > 0 || closed;
+
+            if closed {
+                window.child.wait()?;
+
+                workspace.remove(token)?;
+                throttles.remove(&token);
+                if workspace.windows.is_empty() {
+                    break 'main;
+                }
+            } else if more {
+                pending.push_back(token);
+                wait = Some(Duration::ZERO);
+            }
+        }
+
+        // Closing a window can move the focus to one that takes the input
+        // that was held back
+        let held = connections.attached
+            .and_then(|token| connections.map.get(&token))
+            .is_some_and(|conn| !conn.held.is_empty());
+        if held && !workspace.input_blocked() {
+            wait = Some(Duration::ZERO);
+        }
+        if connections.attached.is_some() && !held {
+            wait = sooner(wait, parser.cut_off().map(|deadline| deadline.saturating_duration_since(Instant::now())));
+        }
+
+        // The mode is shown in the focused window's title, so it going back
+        // to normal once the arrow keys stop repeating needs a frame too
+        let now = Instant::now();
+        dirty |= parser.expire(now);
+        workspace.mode = parser.mode();
+        wait = sooner(wait, parser.deadline().map(|deadline| deadline.saturating_duration_since(now)));
+
+        // Nobody is looking or nothing changed, no need to draw. Neither
+        // while the client is still drawing the last frame: Everything until
+        // it's done goes into the next one, so that slow terminals skip
+        // frames instead of falling behind.
+        let client = match connections.attached {
+            Some(token) if dirty && connections.map.get(&token).is_some_and(|conn| !conn.drawing) => token,
+            _ => {
+                timeout = wait;
+                continue;
+            }
+        };
+
+        // Everything that happens until the interval is over ends up in
+        // the same frame
+        let elapsed = last.elapsed();
+        if elapsed >= interval {
+            dirty = false;
+            workspace.render();
+
+            let mut frame = Vec::new();
+            workspace.buffer.draw(&mut frame)?;
+            if !frame.is_empty() {
+                connections.send(&workspace.poll, client, &Message::Output(frame));
+                if let Some(conn) = connections.map.get_mut(&client) {
+                    conn.drawing = true;
+                }
+            }
+
+            timeout = wait;
+            last = Instant::now();
+        } else {
+            timeout = sooner(Some(interval - elapsed), wait);
+        }
+    }
+
+    if let Some(token) = connections.attached {
+        connections.send(&workspace.poll, token, &Message::Exit);
+    }
+    Ok(())
+}
+
+/// Handle `buf`, or give up on a sequence that was cut off if there is none
+fn input(workspace: &mut Workspace, parser: &mut Parser, buf: Option<&[u8]>) -> Result<()> {
+    let performer = |event: Event| match event {
+        Event::Input(bytes) => workspace.write_all(bytes).map_err(|err| err.into()),
+        Event::Mouse(mouse) => workspace.mouse(mouse),
+        Event::Command(command) => workspace.command(command)
+    };
+    match buf {
+        Some(buf) => parser.feed(buf, performer)?,
+        None => parser.flush(performer)?
+    }
+    workspace.flush()?;
+    Ok(())
+}
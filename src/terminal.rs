@@ -0,0 +1,360 @@
+use super::{
+    buffer::*,
+    color::{Color, ColorDepth},
+    layout::Rect
+};
+
+use std::io::{self, prelude::*};
+use terminfo::{expand::{Context, Parameter}, Database, Expand, Value};
+
+/// Each flag and the SGR parameter that turns it on
+const SGR_ON: [(u16, &str); 10] = [
+    (EFFECT_BOLD, "1"),
+    (EFFECT_DIM, "2"),
+    (EFFECT_ITALIC, "3"),
+    (EFFECT_UNDERLINE, "4"),
+    (EFFECT_UNDERLINE_DOUBLE, "4:2"),
+    (EFFECT_UNDERLINE_CURLY, "4:3"),
+    (EFFECT_BLINK, "5"),
+    (EFFECT_REVERSE, "7"),
+    (EFFECT_HIDDEN, "8"),
+    (EFFECT_STRIKETHROUGH, "9")
+];
+/// The SGR parameters that turn flags off. Some turn off more than one.
+const SGR_OFF: [(u16, &str); 7] = [
+    (EFFECT_BOLD | EFFECT_DIM, "22"),
+    (EFFECT_ITALIC, "23"),
+    (EFFECT_UNDERLINES, "24"),
+    (EFFECT_BLINK, "25"),
+    (EFFECT_REVERSE, "27"),
+    (EFFECT_HIDDEN, "28"),
+    (EFFECT_STRIKETHROUGH, "29")
+];
+/// Each flag and the capability that turns it on
+const EFFECT_CAPS: [(u16, &str, &str); 8] = [
+    (EFFECT_BOLD, "bold", "\x1b[1m"),
+    (EFFECT_DIM, "dim", "\x1b[2m"),
+    (EFFECT_ITALIC, "sitm", "\x1b[3m"),
+    (EFFECT_UNDERLINE, "smul", "\x1b[4m"),
+    (EFFECT_BLINK, "blink", "\x1b[5m"),
+    (EFFECT_REVERSE, "rev", "\x1b[7m"),
+    (EFFECT_HIDDEN, "invis", "\x1b[8m"),
+    (EFFECT_STRIKETHROUGH, "smxx", "\x1b[9m")
+];
+
+/// Expand a parameterized capability, leaving out padding since nothing
+/// this talks to needs it
+fn tparm(cap: &[u8], params: &[Parameter]) -> io::Result<Vec<u8>> {
+    let mut cap = cap.to_vec();
+    while let Some(start) = cap.windows(2).position(|w| w == b"$<") {
+        let end = cap[start..].iter().position(|&b| b == b'>').map_or(cap.len(), |end| start + end + 1);
+        cap.drain(start..end);
+    }
+
+    let mut output = Vec::new();
+    cap[..].expand(&mut output, params, &mut Context::default())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+    Ok(output)
+}
+
+/// What the host terminal can do and how to ask it to, from the terminfo
+/// entry of its `$TERM`. Anything the entry leaves out is not done, and
+/// without an entry at all this assumes xterm.
+#[derive(Debug)]
+pub struct Terminal {
+    /// Switch to the alternate screen, or clear the screen without one
+    enter: Vec<u8>,
+    leave: Vec<u8>,
+    cup: Vec<u8>,
+    show_cursor: Vec<u8>,
+    hide_cursor: Vec<u8>,
+    /// DECSCUSR, taking the shape
+    cursor_shape: Option<Vec<u8>>,
+    /// Whether xterm's mouse reports work, which is guessed from whether
+    /// there is a key for them
+    mouse: bool,
+    /// OSC 52, taking the selection and the base64 text
+    clipboard: Option<Vec<u8>>,
+    /// DECSTBM, taking the top and bottom row
+    scroll_region: Option<Vec<u8>>,
+    /// DECSLRM, taking the left and right column, and what turns the
+    /// margins off again. Only used when asked for, since most terminals
+    /// that call themselves xterm don't have them and scroll whole rows.
+    margins: Option<(Vec<u8>, Vec<u8>)>,
+    /// Scroll up and down one line at the bottom and top of the region,
+    /// and by any number of lines if the host can
+    index: Vec<u8>,
+    reverse_index: Vec<u8>,
+    index_n: Option<Vec<u8>>,
+    reverse_index_n: Option<Vec<u8>>,
+    /// `EFFECT_*` flags the host can show
+    effects: u16,
+    /// Whether the host knows the `4:2` and `4:3` underline styles
+    underline_styles: bool,
+    /// Whether the host knows underline colors
+    underline_color: bool,
+    /// What colors the host can show, if any
+    colors: Option<ColorDepth>,
+    /// Whether attributes are all SGR, so that changes can be combined into
+    /// one sequence. Otherwise each attribute is set with its capability.
+    ansi: bool,
+    sgr0: Vec<u8>,
+    on: Vec<(u16, Vec<u8>)>,
+    setaf: Option<Vec<u8>>,
+    setab: Option<Vec<u8>>
+}
+impl Default for Terminal {
+    fn default() -> Self {
+        Self::from_database(None, ColorDepth::TrueColor, false)
+    }
+}
+impl Terminal {
+    /// Read the terminfo entry for `name`. Colors are limited to `colors`,
+    /// which may be more or less than the entry says. Left and right
+    /// margins are only used if `margins` says the host really has them.
+    pub fn new(name: &str, colors: ColorDepth, margins: bool) -> Self {
+        Self::from_database(Database::from_name(name).ok().as_ref(), colors, margins)
+    }
+    fn from_database(db: Option<&Database>, colors: ColorDepth, margins: bool) -> Self {
+        // Capabilities are only made up when there is no entry to go by
+        let string = |name: &str, xterm: &str| -> Option<Vec<u8>> {
+            match db {
+                Some(db) => match db.raw(name) {
+                    Some(Value::String(value)) => Some(value.clone()),
+                    _ => None
+                },
+                None => Some(xterm.as_bytes().to_vec())
+            }
+        };
+        let fixed = |name: &str, xterm: &str| -> Vec<u8> {
+            string(name, xterm).and_then(|cap| tparm(&cap, &[]).ok()).unwrap_or_default()
+        };
+
+        let clear = fixed("clear", "\x1b[H\x1b[2J");
+        let (enter, leave) = match (string("smcup", "\x1b[?1049h"), string("rmcup", "\x1b[?1049l")) {
+            (Some(_), Some(_)) => (fixed("smcup", ""), fixed("rmcup", "")),
+            _ => (clear.clone(), clear)
+        };
+
+        let sgr0 = fixed("sgr0", "\x1b(B\x1b[m");
+        let mut effects = 0;
+        let mut on = Vec::new();
+        // sgr0 may also reset the character set, like xterm's does
+        let mut ansi = [&b"\x1b[m"[..], b"\x1b[0m"].iter().any(|reset| sgr0.windows(reset.len()).any(|w| w == *reset));
+        for &(flag, name, xterm) in &EFFECT_CAPS {
+            if let Some(cap) = string(name, xterm).and_then(|cap| tparm(&cap, &[]).ok()) {
+                ansi &= cap.starts_with(b"\x1b[") && cap.ends_with(b"m");
+                effects |= flag;
+                on.push((flag, cap));
+            }
+        }
+        let setaf = string("setaf", "");
+        let setab = string("setab", "");
+
+        Self {
+            enter,
+            leave,
+            cup: string("cup", "\x1b[%i%p1%d;%p2%dH").unwrap_or_else(|| b"\x1b[%i%p1%d;%p2%dH".to_vec()),
+            show_cursor: fixed("cnorm", "\x1b[?25h"),
+            hide_cursor: fixed("civis", "\x1b[?25l"),
+            cursor_shape: string("Ss", "\x1b[%p1%d q"),
+            mouse: string("kmous", "\x1b[M").is_some(),
+            clipboard: string("Ms", "\x1b]52;%p1%s;%p2%s\x07"),
+            scroll_region: string("csr", "\x1b[%i%p1%d;%p2%dr"),
+            margins: string("smglr", "\x1b[?69h\x1b[%i%p1%d;%p2%ds").zip(string("mgc", "\x1b[?69l"))
+                .filter(|_| margins),
+            index: fixed("ind", "\n"),
+            reverse_index: fixed("ri", "\x1bM"),
+            index_n: string("indn", "\x1b[%p1%dS"),
+            reverse_index_n: string("rin", "\x1b[%p1%dT"),
+            effects,
+            underline_styles: string("Smulx", "\x1b[4:%p1%dm").is_some(),
+            // xterm doesn't know these either
+            underline_color: db.is_some() && string("Setulc", "").is_some(),
+            colors: setaf.as_ref().map(|_| colors),
+            ansi,
+            sgr0,
+            on,
+            setaf,
+            setab
+        }
+    }
+
+    /// Set the host up for drawing on
+    pub fn start<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.enter)?;
+        w.write_all(&self.hide_cursor)?;
+        if self.mouse {
+            w.write_all(b"\x1b[?1000h\x1b[?1002h\x1b[?1006h")?;
+        }
+        Ok(())
+    }
+    /// Undo `start` and anything drawing changed
+    pub fn restore<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        if self.mouse {
+            w.write_all(b"\x1b[?1006l\x1b[?1003l\x1b[?1002l\x1b[?1000l")?;
+        }
+        w.write_all(&self.sgr0)?;
+        self.cursor_shape(w, 0)?;
+        w.write_all(&self.show_cursor)?;
+        w.write_all(&self.leave)
+    }
+    /// Move the cursor, 0-based
+    pub fn move_to<W: Write>(&self, w: &mut W, x: u16, y: u16) -> io::Result<()> {
+        w.write_all(&tparm(&self.cup, &[Parameter::from(y), Parameter::from(x)])?)
+    }
+    pub fn show_cursor<W: Write>(&self, w: &mut W, show: bool) -> io::Result<()> {
+        w.write_all(if show { &self.show_cursor } else { &self.hide_cursor })
+    }
+    /// Set the cursor shape like DECSCUSR, 0 being the default
+    pub fn cursor_shape<W: Write>(&self, w: &mut W, shape: u8) -> io::Result<()> {
+        match self.cursor_shape {
+            Some(ref cap) => w.write_all(&tparm(cap, &[Parameter::from(shape)])?),
+            None => Ok(())
+        }
+    }
+    /// Ask for pointer motion without any button held, or stop asking
+    pub fn mouse_motion<W: Write>(&self, w: &mut W, enable: bool) -> io::Result<()> {
+        if !self.mouse {
+            return Ok(());
+        }
+        write!(w, "\x1b[?1003{}", if enable { 'h' } else { 'l' })
+    }
+    /// Put base64 encoded text on the host's clipboard
+    pub fn set_clipboard<W: Write>(&self, w: &mut W, base64: &str) -> io::Result<()> {
+        match self.clipboard {
+            Some(ref cap) => w.write_all(&tparm(cap, &[Parameter::from("c"), Parameter::from(base64)])?),
+            None => Ok(())
+        }
+    }
+
+    /// Returns true if the host can scroll part of the screen, which is a
+    /// number of whole rows unless `partial`
+    pub fn can_scroll(&self, partial: bool) -> bool {
+        self.scroll_region.is_some() && (!partial || self.margins.is_some())
+    }
+    /// Scroll `rect` of a screen that is `width` by `height` up by `lines`,
+    /// or down if negative. This moves the cursor, and new lines get the
+    /// current pen's background.
+    pub fn scroll<W: Write>(&self, w: &mut W, rect: Rect, lines: isize, (width, height): (u16, u16)) -> io::Result<()> {
+        let scroll_region = match self.scroll_region {
+            Some(ref cap) => cap,
+            None => return Ok(())
+        };
+        let (top, bottom) = (rect.y, rect.y + rect.height - 1);
+        let partial = rect.x != 0 || rect.width != width;
+
+        w.write_all(&tparm(scroll_region, &[Parameter::from(top), Parameter::from(bottom)])?)?;
+        if let (true, Some((set, _))) = (partial, &self.margins) {
+            w.write_all(&tparm(set, &[Parameter::from(rect.x), Parameter::from(rect.x + rect.width - 1)])?)?;
+        }
+        let n = lines.unsigned_abs();
+        let (one, many, y) = if lines > 0 {
+            (&self.index, &self.index_n, bottom)
+        } else {
+            (&self.reverse_index, &self.reverse_index_n, top)
+        };
+        match many {
+            Some(cap) if n > 1 => w.write_all(&tparm(cap, &[Parameter::from(n as i32)])?)?,
+            _ => {
+                self.move_to(w, rect.x, y)?;
+                for _ in 0..n {
+                    w.write_all(one)?;
+                }
+            }
+        }
+        if let (true, Some((_, reset))) = (partial, &self.margins) {
+            w.write_all(reset)?;
+        }
+        w.write_all(&tparm(scroll_region, &[Parameter::from(0), Parameter::from(height - 1)])?)
+    }
+
+    /// Returns the attributes of `c` that the host can show, with colors
+    /// made to fit
+    fn visible(&self, c: &Char) -> Char {
+        let mut flags = c.flags & (self.effects | EFFECT_UNDERLINE_DOUBLE | EFFECT_UNDERLINE_CURLY);
+        if self.effects & EFFECT_UNDERLINE == 0 {
+            flags &= !EFFECT_UNDERLINES;
+        } else if !self.underline_styles && flags & EFFECT_UNDERLINES != 0 {
+            flags = (flags & !EFFECT_UNDERLINES) | EFFECT_UNDERLINE;
+        }
+        let color = |color: Color| self.colors.map_or(Color::Default, |depth| color.downsample(depth));
+        Char {
+            flags,
+            fg: color(c.fg),
+            bg: color(c.bg),
+            underline: if self.underline_color { color(c.underline) } else { Color::Default },
+            ..*c
+        }
+    }
+    /// Change the host's pen from `pen`, or from an unknown state if `None`,
+    /// to the attributes of `c`, as far as the host can show them
+    pub fn pen<W: Write>(&self, w: &mut W, pen: Option<Char>, c: &Char) -> io::Result<()> {
+        let c = self.visible(c);
+        let pen = pen.map(|pen| self.visible(&pen));
+        if self.ansi {
+            return self.sgr(w, pen, &c);
+        }
+
+        if pen.is_some_and(|pen| (pen.flags, pen.fg, pen.bg) == (c.flags, c.fg, c.bg)) {
+            return Ok(());
+        }
+        // Without SGR there is no turning single attributes off
+        w.write_all(&self.sgr0)?;
+        for (flag, cap) in &self.on {
+            if c.flags & flag != 0 {
+                w.write_all(cap)?;
+            }
+        }
+        for (color, cap) in [(c.fg, &self.setaf), (c.bg, &self.setab)] {
+            let index = match color.downsample(ColorDepth::Ansi256) {
+                Color::Ansi(index) => index,
+                _ => continue
+            };
+            if let Some(cap) = cap {
+                w.write_all(&tparm(cap, &[Parameter::from(index)])?)?;
+            }
+        }
+        Ok(())
+    }
+    /// Like `pen`, but with as few SGR parameters as it can
+    fn sgr<W: Write>(&self, w: &mut W, pen: Option<Char>, c: &Char) -> io::Result<()> {
+        let mut params = Vec::new();
+        // An unknown pen is reset, which leaves it with the default colors
+        if pen.is_none() {
+            params.push(String::from("0"));
+        }
+        let pen = pen.unwrap_or(SPACE);
+
+        let off = pen.flags & !c.flags;
+        let mut on = c.flags & !pen.flags;
+        for &(flags, param) in &SGR_OFF {
+            if off & flags != 0 {
+                params.push(String::from(param));
+                // Turn back on what shouldn't have been turned off
+                on |= c.flags & flags;
+            }
+        }
+        for &(flag, param) in &SGR_ON {
+            if on & flag != 0 {
+                params.push(String::from(param));
+            }
+        }
+
+        let depth = self.colors.unwrap_or(ColorDepth::Ansi8);
+        if pen.fg != c.fg {
+            params.push(c.fg.sgr(38, depth));
+        }
+        if pen.bg != c.bg {
+            params.push(c.bg.sgr(48, depth));
+        }
+        if pen.underline != c.underline {
+            params.push(c.underline.sgr(58, depth));
+        }
+
+        if params.is_empty() {
+            return Ok(());
+        }
+        write!(w, "\x1b[{}m", params.join(";"))
+    }
+}
@@ -0,0 +1,487 @@
+use super::{
+    buffer::*,
+    color::Color,
+    config::AmbiguousWidth,
+    key::MouseEncoding
+};
+
+use ransid::{Event, State};
+use std::cmp;
+use vte::{Params, ParamsIter};
+
+/// Which mouse events a program asked for
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MouseTracking {
+    #[default]
+    Off,
+    /// Presses and releases (1000)
+    Normal,
+    /// Also motion while a button is held (1002)
+    Button,
+    /// Also motion without any button held (1003)
+    Any
+}
+
+/// The mouse modes a program has set with DECSET
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MouseMode {
+    pub tracking: MouseTracking,
+    pub encoding: MouseEncoding
+}
+impl Default for MouseMode {
+    fn default() -> Self {
+        Self {
+            tracking: MouseTracking::Off,
+            encoding: MouseEncoding::X10
+        }
+    }
+}
+impl MouseMode {
+    /// Set or reset a DECSET mode. Returns false if it isn't a mouse mode.
+    fn set(&mut self, mode: i64, enable: bool) -> bool {
+        let tracking = match mode {
+            1000 => Some(MouseTracking::Normal),
+            1002 => Some(MouseTracking::Button),
+            1003 => Some(MouseTracking::Any),
+            _ => None
+        };
+        let encoding = match mode {
+            1005 => Some(MouseEncoding::Utf8),
+            1006 => Some(MouseEncoding::Sgr),
+            1015 => Some(MouseEncoding::Urxvt),
+            _ => None
+        };
+
+        match (tracking, encoding) {
+            (Some(tracking), _) => if enable {
+                self.tracking = tracking;
+            } else if self.tracking == tracking {
+                self.tracking = MouseTracking::Off;
+            },
+            (_, Some(encoding)) => if enable {
+                self.encoding = encoding;
+            } else if self.encoding == encoding {
+                self.encoding = MouseEncoding::X10;
+            },
+            (None, None) => return false
+        }
+        true
+    }
+}
+
+/// The attributes new characters get. ransid keeps track of colors too, but
+/// has no way to say "the host's default".
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Pen {
+    /// `EFFECT_*` flags
+    pub flags: u16,
+    pub fg: Color,
+    pub bg: Color,
+    /// The underline color, where the default is to use `fg`
+    pub underline: Color
+}
+
+/// What a program's output does to its window
+pub enum Output<'a> {
+    /// Any of ransid's events except for `Event::Char`, which is turned
+    /// into `Print` because it can't hold more than one char
+    Ransid(Event<'a>),
+    /// Something was printed at a cell, or an earlier cluster grew
+    Print { x: usize, y: usize, content: Grapheme }
+}
+
+/// Parses a program's output and feeds it to ransid, keeping track of the
+/// things ransid doesn't know about on the way
+pub struct Tracker {
+    parser: vte::Parser,
+    pub state: State,
+    pub mouse: MouseMode,
+    /// The cursor shape set with DECSCUSR, 0 being the host's default
+    pub cursor_shape: u8,
+    pub pen: Pen,
+    ambiguous: AmbiguousWidth,
+    /// The last printed cell, which combining marks are added to
+    last: Option<(usize, usize, Grapheme)>
+}
+impl Tracker {
+    pub fn new(width: usize, height: usize, ambiguous: AmbiguousWidth) -> Self {
+        Self {
+            parser: vte::Parser::new(),
+            state: State::new(width, height),
+            mouse: MouseMode::default(),
+            cursor_shape: 0,
+            pen: Pen::default(),
+            ambiguous,
+            last: None
+        }
+    }
+    /// Returns the cursor position if it should be shown. ransid lets it
+    /// sit just past the last column until the next character wraps.
+    pub fn cursor(&self) -> Option<(u16, u16)> {
+        if !self.state.cursor || self.state.w == 0 || self.state.h == 0 {
+            return None;
+        }
+        Some((
+            self.state.x.min(self.state.w - 1) as u16,
+            self.state.y.min(self.state.h - 1) as u16
+        ))
+    }
+    /// Like `ransid::Console::resize`
+    pub fn resize(&mut self, width: usize, height: usize) {
+        let state = &mut self.state;
+
+        state.top_margin = cmp::max(0, cmp::min(height as isize - 1, state.top_margin as isize)) as usize;
+        state.bottom_margin = cmp::max(
+            state.top_margin as isize,
+            cmp::min(height as isize - 1, state.bottom_margin as isize + height as isize - state.h as isize)
+        ) as usize;
+
+        state.w = width;
+        state.h = height;
+    }
+    /// Parse `bytes`, calling `callback` with everything that happens and
+    /// the pen at the time
+    pub fn write<F: FnMut(Output, Pen)>(&mut self, bytes: &[u8], mut callback: F) {
+        let mut performer = Performer {
+            state: &mut self.state,
+            mouse: &mut self.mouse,
+            cursor_shape: &mut self.cursor_shape,
+            pen: &mut self.pen,
+            ambiguous: self.ambiguous,
+            last: &mut self.last,
+            callback: &mut callback
+        };
+        self.parser.advance(&mut performer, bytes);
+    }
+}
+
+/// Read an extended color, either from the subparameters of `38:5:n` or
+/// `38:2::r:g:b`, or from the parameters following `38;5;n` or `38;2;r;g;b`
+fn extended_color(sub: &[u16], params: &mut ParamsIter) -> Option<Color> {
+    let mut next = |i: usize| match sub.get(i) {
+        Some(&n) => Some(n),
+        None if sub.is_empty() => params.next().map(|param| param[0]),
+        None => None
+    };
+    match next(0)? {
+        5 => Some(Color::Ansi(next(1)? as u8)),
+        2 => {
+            // The colon form may have a color space id first, which is
+            // usually left empty
+            let skip = if sub.len() > 4 { 1 } else { 0 };
+            let (r, g, b) = (next(1 + skip)?, next(2 + skip)?, next(3 + skip)?);
+            Some(Color::Rgb(r as u8, g as u8, b as u8))
+        },
+        _ => None
+    }
+}
+
+struct Performer<'a, F: FnMut(Output, Pen)> {
+    state: &'a mut State,
+    mouse: &'a mut MouseMode,
+    cursor_shape: &'a mut u8,
+    pen: &'a mut Pen,
+    ambiguous: AmbiguousWidth,
+    last: &'a mut Option<(usize, usize, Grapheme)>,
+    callback: &'a mut F
+}
+impl<'a, F: FnMut(Output, Pen)> Performer<'a, F> {
+    /// Returns ransid's state and a callback for it that passes the pen
+    /// along with each event
+    fn ransid(&mut self) -> (&mut State, impl FnMut(Event) + '_) {
+        let (callback, pen) = (&mut *self.callback, *self.pen);
+        (&mut *self.state, move |event| callback(match event {
+            Event::Char { x, y, c, .. } => Output::Print { x, y, content: c.into() },
+            event => Output::Ransid(event)
+        }, pen))
+    }
+    /// Answer a query
+    fn reply(&mut self, data: &[u8]) {
+        (self.callback)(Output::Ransid(Event::Input { data }), *self.pen);
+    }
+    /// Select graphic rendition. ransid never sees these, it doesn't know
+    /// about most attributes.
+    fn sgr(&mut self, params: &Params) {
+        let pen = &mut *self.pen;
+
+        let mut params = params.iter();
+        while let Some(param) = params.next() {
+            match *param {
+                [0] => *pen = Pen::default(),
+                [1] => pen.flags |= EFFECT_BOLD,
+                [2] => pen.flags |= EFFECT_DIM,
+                [3] => pen.flags |= EFFECT_ITALIC,
+                [4] | [4, _, ..] | [21] => {
+                    pen.flags &= !EFFECT_UNDERLINES;
+                    pen.flags |= match *param {
+                        [4, 0, ..] => 0,
+                        [4, 2, ..] | [21] => EFFECT_UNDERLINE_DOUBLE,
+                        [4, 3, ..] => EFFECT_UNDERLINE_CURLY,
+                        _ => EFFECT_UNDERLINE
+                    };
+                },
+                [5] | [6] => pen.flags |= EFFECT_BLINK,
+                [7] => pen.flags |= EFFECT_REVERSE,
+                [8] => pen.flags |= EFFECT_HIDDEN,
+                [9] => pen.flags |= EFFECT_STRIKETHROUGH,
+                [22] => pen.flags &= !(EFFECT_BOLD | EFFECT_DIM),
+                [23] => pen.flags &= !EFFECT_ITALIC,
+                [24] => pen.flags &= !EFFECT_UNDERLINES,
+                [25] => pen.flags &= !EFFECT_BLINK,
+                [27] => pen.flags &= !EFFECT_REVERSE,
+                [28] => pen.flags &= !EFFECT_HIDDEN,
+                [29] => pen.flags &= !EFFECT_STRIKETHROUGH,
+                [n @ 30..=37] => pen.fg = Color::Ansi(n as u8 - 30),
+                [38, ref sub @ ..] => if let Some(color) = extended_color(sub, &mut params) {
+                    pen.fg = color;
+                },
+                [39] => pen.fg = Color::Default,
+                [n @ 40..=47] => pen.bg = Color::Ansi(n as u8 - 40),
+                [48, ref sub @ ..] => if let Some(color) = extended_color(sub, &mut params) {
+                    pen.bg = color;
+                },
+                [49] => pen.bg = Color::Default,
+                [58, ref sub @ ..] => if let Some(color) = extended_color(sub, &mut params) {
+                    pen.underline = color;
+                },
+                [59] => pen.underline = Color::Default,
+                [n @ 90..=97] => pen.fg = Color::Ansi(n as u8 - 90 + 8),
+                [n @ 100..=107] => pen.bg = Color::Ansi(n as u8 - 100 + 8),
+                _ => ()
+            }
+        }
+    }
+}
+impl<'a, F: FnMut(Output, Pen)> vte::Perform for Performer<'a, F> {
+    fn print(&mut self, c: char) {
+        if let Some((x, y, mut content)) = *self.last {
+            if content.extend(c) {
+                *self.last = Some((x, y, content));
+                (self.callback)(Output::Print { x, y, content }, *self.pen);
+                return;
+            }
+        }
+        let width = match self.ambiguous.width(c) {
+            // A combining mark without anything to combine with
+            0 => return,
+            width => width.min(self.state.w)
+        };
+
+        let state = &mut *self.state;
+        if width == 2 && state.x + 1 >= state.w {
+            // Both halves have to fit on the line
+            if !state.autowrap {
+                state.x = state.w - 2;
+            } else if state.x < state.w {
+                state.x = state.w;
+            }
+        }
+
+        let (callback, pen) = (&mut *self.callback, *self.pen);
+        let mut at = (0, 0);
+        state.print(c, &mut |event| match event {
+            Event::Char { x, y, .. } => at = (x, y),
+            event => callback(Output::Ransid(event), pen)
+        });
+
+        let (x, y) = at;
+        let content = Grapheme::new(c, width as u8);
+        callback(Output::Print { x, y, content }, pen);
+        if width == 2 {
+            callback(Output::Print { x: x + 1, y, content: Grapheme::CONTINUATION }, pen);
+            state.x += 1;
+        }
+        *self.last = Some((x, y, content));
+    }
+    fn execute(&mut self, byte: u8) {
+        *self.last = None;
+        let (state, mut callback) = self.ransid();
+        state.execute(byte as char, &mut callback);
+    }
+    fn osc_dispatch(&mut self, params: &[&[u8]], _bell_terminated: bool) {
+        let (state, mut callback) = self.ransid();
+        state.osc(params, &mut callback);
+    }
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], _ignore: bool, c: char) {
+        *self.last = None;
+        if c == 'm' && intermediates.is_empty() {
+            self.sgr(params);
+            return;
+        }
+
+        // ransid has no use for subparameters
+        let params: Vec<i64> = params.iter().map(|param| param[0] as i64).collect();
+        if intermediates == b"?" && (c == 'h' || c == 'l') {
+            // ransid only looks at the first mode, so hand them over one by
+            // one, except for the mouse modes which are ours
+            for &mode in &params {
+                if !self.mouse.set(mode, c == 'h') {
+                    let (state, mut callback) = self.ransid();
+                    state.csi(c, &[mode], intermediates, &mut callback);
+                }
+            }
+            return;
+        }
+        match (c, intermediates, params.first().copied().unwrap_or(0)) {
+            // Secondary device attributes: A VT220, but ransid would answer
+            // this like it's the primary one
+            ('c', b">", 0) => self.reply(b"\x1b[>1;10;0c"),
+            ('c', [_, ..], _) => (),
+            // Things like xterm's key modifier options, not SGR
+            ('m', [_, ..], _) => (),
+            // Set the cursor shape
+            ('q', b" ", shape @ 0..=6) => *self.cursor_shape = shape as u8,
+            // Device status
+            ('n', b"", 5) => self.reply(b"\x1b[0n"),
+            // Extended cursor position, which ransid doesn't know
+            ('n', b"?", 6) => {
+                let report = format!("\x1b[?{};{}R", self.state.y + 1, self.state.x + 1);
+                self.reply(report.as_bytes());
+            },
+            _ => {
+                let (state, mut callback) = self.ransid();
+                state.csi(c, &params, intermediates, &mut callback);
+            }
+        }
+    }
+    fn esc_dispatch(&mut self, intermediates: &[u8], _ignore: bool, byte: u8) {
+        *self.last = None;
+        if byte == b'c' && intermediates.is_empty() {
+            // A full reset, which ransid does to its own state
+            *self.pen = Pen::default();
+        }
+        let (state, mut callback) = self.ransid();
+        state.esc(byte as char, &[], intermediates, &mut callback);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns everything the tracker answers to `query`
+    fn reply(tracker: &mut Tracker, query: &[u8]) -> String {
+        let mut reply = Vec::new();
+        tracker.write(query, |output, _| if let Output::Ransid(Event::Input { data }) = output {
+            reply.extend_from_slice(data);
+        });
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn cursor_position() {
+        let mut tracker = Tracker::new(80, 24, AmbiguousWidth::Narrow);
+        assert_eq!(reply(&mut tracker, b"\x1b[6n"), "\x1b[1;1R");
+        assert_eq!(reply(&mut tracker, b"\x1b[5;10H\x1b[6n"), "\x1b[5;10R");
+        assert_eq!(reply(&mut tracker, b"abc\x1b[?6n"), "\x1b[?5;13R");
+    }
+    #[test]
+    fn device_status() {
+        let mut tracker = Tracker::new(80, 24, AmbiguousWidth::Narrow);
+        assert_eq!(reply(&mut tracker, b"\x1b[5n"), "\x1b[0n");
+    }
+    #[test]
+    fn device_attributes() {
+        let mut tracker = Tracker::new(80, 24, AmbiguousWidth::Narrow);
+        assert_eq!(reply(&mut tracker, b"\x1b[c"), "\x1b[?6c");
+        assert_eq!(reply(&mut tracker, b"\x1b[0c"), "\x1b[?6c");
+        assert_eq!(reply(&mut tracker, b"\x1b[>c"), "\x1b[>1;10;0c");
+        assert_eq!(reply(&mut tracker, b"\x1b[=c"), "");
+    }
+    #[test]
+    fn cursor_state() {
+        let mut tracker = Tracker::new(10, 5, AmbiguousWidth::Narrow);
+        assert_eq!(tracker.cursor(), Some((0, 0)));
+        reply(&mut tracker, b"0123456789");
+        assert_eq!(tracker.cursor(), Some((9, 0)));
+        reply(&mut tracker, b"\x1b[?25l\x1b[5 q");
+        assert_eq!(tracker.cursor(), None);
+        assert_eq!(tracker.cursor_shape, 5);
+        reply(&mut tracker, b"\x1b[?25h\x1b[ q");
+        assert_eq!(tracker.cursor(), Some((9, 0)));
+        assert_eq!(tracker.cursor_shape, 0);
+    }
+    /// Returns the pen of the last character in `output`
+    fn pen(tracker: &mut Tracker, output: &[u8]) -> Pen {
+        let mut last = None;
+        tracker.write(output, |output, pen| if let Output::Print { .. } = output {
+            last = Some(pen);
+        });
+        last.unwrap()
+    }
+
+    #[test]
+    fn sgr() {
+        let mut tracker = Tracker::new(80, 24, AmbiguousWidth::Narrow);
+        assert_eq!(pen(&mut tracker, b"\x1b[1;3;4mx").flags, EFFECT_BOLD | EFFECT_ITALIC | EFFECT_UNDERLINE);
+        assert_eq!(pen(&mut tracker, b"\x1b[4:3;22mx").flags, EFFECT_ITALIC | EFFECT_UNDERLINE_CURLY);
+        assert_eq!(pen(&mut tracker, b"\x1b[21;9;23mx").flags, EFFECT_UNDERLINE_DOUBLE | EFFECT_STRIKETHROUGH);
+        assert_eq!(pen(&mut tracker, b"\x1b[mx"), Pen::default());
+
+        assert_eq!(pen(&mut tracker, b"\x1b[58:2::1:2:3;38;2;4;5;6;41mx"), Pen {
+            flags: 0,
+            fg: Color::Rgb(4, 5, 6),
+            bg: Color::Ansi(1),
+            underline: Color::Rgb(1, 2, 3)
+        });
+        assert_eq!(pen(&mut tracker, b"\x1b[58;5;1;38:5:2;7;103mx"), Pen {
+            flags: EFFECT_REVERSE,
+            fg: Color::Ansi(2),
+            bg: Color::Ansi(11),
+            underline: Color::Ansi(1)
+        });
+        assert_eq!(pen(&mut tracker, b"\x1b[59;27;39;49mx"), Pen::default());
+    }
+    /// Returns every cell printed by `output`, as it was last printed
+    fn cells(tracker: &mut Tracker, output: &str) -> Vec<(usize, usize, String)> {
+        let mut cells = Vec::new();
+        tracker.write(output.as_bytes(), |output, _| if let Output::Print { x, y, content } = output {
+            cells.retain(|&(cx, cy, _)| (cx, cy) != (x, y));
+            cells.push((x, y, content.to_string()));
+        });
+        cells
+    }
+
+    #[test]
+    fn graphemes() {
+        let mut tracker = Tracker::new(10, 5, AmbiguousWidth::Narrow);
+        assert_eq!(cells(&mut tracker, "e\u{301}\u{302}x"), vec![
+            (0, 0, String::from("e\u{301}\u{302}")),
+            (1, 0, String::from("x"))
+        ]);
+        assert_eq!(tracker.cursor(), Some((2, 0)));
+
+        // Emoji joined into one wide cluster
+        assert_eq!(cells(&mut tracker, "\u{1f468}\u{200d}\u{1f469}"), vec![
+            (3, 0, String::new()),
+            (2, 0, String::from("\u{1f468}\u{200d}\u{1f469}"))
+        ]);
+        assert_eq!(tracker.cursor(), Some((4, 0)));
+
+        // Nothing to combine with after the cursor moved
+        assert_eq!(cells(&mut tracker, "\x1b[2;1H\u{301}"), vec![]);
+    }
+    #[test]
+    fn wide() {
+        let mut tracker = Tracker::new(5, 3, AmbiguousWidth::Narrow);
+        assert_eq!(cells(&mut tracker, "ab\u{4e2d}\u{6587}"), vec![
+            (0, 0, String::from("a")),
+            (1, 0, String::from("b")),
+            (2, 0, String::from("\u{4e2d}")),
+            (3, 0, String::new()),
+            // Wrapped early, since it doesn't fit in the last column
+            (0, 1, String::from("\u{6587}")),
+            (1, 1, String::new())
+        ]);
+        assert_eq!(tracker.cursor(), Some((2, 1)));
+
+        let mut tracker = Tracker::new(5, 3, AmbiguousWidth::Wide);
+        cells(&mut tracker, "\u{b0}");
+        assert_eq!(tracker.cursor(), Some((2, 0)));
+    }
+    #[test]
+    fn no_reply_to_output() {
+        let mut tracker = Tracker::new(80, 24, AmbiguousWidth::Narrow);
+        assert_eq!(reply(&mut tracker, b"hello\r\n\x1b[1mworld\x1b[0m"), "");
+    }
+}
@@ -1,6 +1,10 @@
 use super::{
     buffer::*,
+    config::Config,
     delaying::DelayingWriter,
+    key::Mouse,
+    layout::Rect,
+    tracker::{MouseTracking, Output, Tracker},
     Result
 };
 
@@ -11,63 +15,233 @@ use nix::{
 };
 
 use pseudoterm::{OpenptyOptions, Winsize, WinsizeSetter};
-use ransid::{Console, Event};
+use ransid::Event;
 use std::{
     collections::VecDeque,
-    ffi::OsStr,
     fs::File,
-    io::prelude::*,
+    io::{self, prelude::*},
     mem,
     ops::{Deref, DerefMut},
+    os::unix::process::CommandExt,
     process::{Command, Child}
 };
+use unicode_segmentation::UnicodeSegmentation;
 
 const RESIZE_LEFT: u8 = 1;
 const RESIZE_RIGHT: u8 = 1 << 1;
 const RESIZE_BOTTOM: u8 = 1 << 2;
 
+/// How many lines that have scrolled off the top are kept per window
+const SCROLLBACK_LIMIT: usize = 1000;
+
+/// A range of selected text. Lines are indexes into the scrollback followed
+/// by the screen, so the selection stays put when the view is scrolled.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Selection {
+    pub start: (usize, u16),
+    pub end: (usize, u16)
+}
+impl Selection {
+    fn ordered(&self) -> ((usize, u16), (usize, u16)) {
+        if self.start <= self.end {
+            (self.start, self.end)
+        } else {
+            (self.end, self.start)
+        }
+    }
+    pub fn contains_line(&self, line: usize) -> bool {
+        let (start, end) = self.ordered();
+        line >= start.0 && line <= end.0
+    }
+    pub fn contains(&self, line: usize, col: u16) -> bool {
+        let (start, end) = self.ordered();
+        (line, col) >= start && (line, col) <= end
+    }
+}
+
 pub struct Window {
-    console: Console,
+    tracker: Tracker,
     inner: WindowInner
 }
 impl Window {
-    pub fn new(cmd: &OsStr, x: u16, y: u16, width: u16, height: u16) -> Result<Self> {
+    pub fn new(cmd: &mut Command, config: &Config, x: u16, y: u16, width: u16, height: u16) -> Result<Self> {
         let (width, height) = (width - 2, height - 2);
 
         Ok(Self {
-            console: Console::new(width as usize, height as usize),
+            tracker: Tracker::new(width as usize, height as usize, config.ambiguous_width),
             inner: WindowInner::new(cmd, x, y, width, height)?
         })
     }
     pub fn resize(&mut self, width: u16, height: u16) -> Result<()> {
         self.inner.resize(width, height)?;
-        self.console.resize(width as usize, height as usize);
+        self.tracker.resize(width as usize, height as usize);
+        #[cfg(feature = "signals")]
+        signal::kill(Pid::from_raw(self.inner.child.id() as _), signal::SIGWINCH)?;
+        Ok(())
+    }
+    /// Move and resize the window so its frame covers `rect`
+    pub fn set_frame(&mut self, rect: Rect) -> Result<()> {
+        self.inner.x = rect.x;
+        self.inner.y = rect.y;
+
+        let (width, height) = (rect.width - 2, rect.height - 2);
+        if width != self.inner.width || height != self.inner.height {
+            self.resize(width, height)?;
+        }
+        Ok(())
+    }
+    /// Ask the program to exit. The window is removed once the pty closes.
+    pub fn close(&mut self) -> Result<()> {
+        #[cfg(feature = "signals")]
+        signal::kill(Pid::from_raw(self.inner.child.id() as _), signal::SIGHUP)?;
+        #[cfg(not(feature = "signals"))]
+        self.inner.child.kill()?;
         Ok(())
     }
     pub fn write(&mut self, buf: &[u8]) {
-        self.inner.write(&mut self.console, buf);
+        self.inner.write(&mut self.tracker, buf);
     }
-    fn render_frame(&self, buf: &mut Buffer, y: u16, start: char, middle: char, end: char) {
+    fn render_frame(&self, buf: &mut Buffer, y: u16, border: Char, [start, middle, end]: [char; 3]) {
         let x = self.inner.x;
 
-        buf.set(x, y, Char::from(start));
-        buf.line(x+1, y, self.inner.width, Char::from(middle));
-        buf.set(x+1+self.inner.width, y, Char::from(end));
+        buf.set(x, y, Char { content: start.into(), ..border });
+        buf.line(x+1, y, self.inner.width, Char { content: middle.into(), ..border });
+        buf.set(x+1+self.inner.width, y, Char { content: end.into(), ..border });
+    }
+    /// Draw the title over the top border, truncated to fit, after `mode`
+    /// if any
+    fn render_title(&self, buf: &mut Buffer, config: &Config, border: Char, focused: bool, mode: Option<&str>) {
+        let text = match mode {
+            Some(mode) if self.inner.title.is_empty() => format!("[{}]", mode),
+            Some(mode) => format!("[{}] {}", mode, self.inner.title),
+            None => self.inner.title.clone()
+        };
+        // Keep a corner, a line and a space on each side
+        let room = (self.inner.width as usize).saturating_sub(4);
+        if text.is_empty() || room == 0 {
+            return;
+        }
+
+        let mut title = Vec::new();
+        for cluster in text.graphemes(true) {
+            let mut chars = cluster.chars();
+            let first = match chars.next() {
+                Some(c) => c,
+                None => continue
+            };
+            let mut content = Grapheme::new(first, config.ambiguous_width.width(first).clamp(1, 2) as u8);
+            for c in chars {
+                content.extend(c);
+            }
+            title.push(content);
+            if content.width() == 2 {
+                title.push(Grapheme::CONTINUATION);
+            }
+        }
+        if title.len() > room {
+            let ellipsis = Grapheme::new('…', config.ambiguous_width.width('…') as u8);
+            title.truncate(room.saturating_sub(ellipsis.width() as usize));
+            // Don't leave the left half of a wide character behind
+            if title.last().is_some_and(|content| content.width() == 2) {
+                title.pop();
+            }
+            title.push(ellipsis);
+            if ellipsis.width() == 2 {
+                title.push(Grapheme::CONTINUATION);
+            }
+        }
+
+        let mut c = Char { fg: config.colors.title, ..border };
+        if focused {
+            // Reversed, so that the default colors swap places
+            c.fg = config.colors.focused_border;
+            c.bg = config.colors.focused_title;
+            c.flags |= EFFECT_REVERSE;
+        }
+        let x = self.inner.x + 2;
+        let y = self.inner.y;
+        buf.set(x, y, c);
+        for (i, &content) in title.iter().enumerate() {
+            buf.set(x + 1 + i as u16, y, Char { content, ..c });
+        }
+        buf.set(x + 1 + title.len() as u16, y, c);
     }
-    pub fn render(&self, buf: &mut Buffer) {
+    /// Draw the window. `mode` is what the keys do, if the window is focused
+    /// and they don't go to it.
+    pub fn render(&self, buf: &mut Buffer, config: &Config, focused: bool, mode: Option<&str>) {
         let x = self.inner.x;
         let mut y = self.inner.y;
+        let width = self.inner.width as usize;
+
+        let mut border = Char { fg: config.colors.border, ..SPACE };
+        if focused {
+            border.fg = config.colors.focused_border;
+            border.flags |= EFFECT_BOLD;
+        }
+        let [top_left, top_right, bottom_left, bottom_right, horizontal, vertical] = config.border.glyphs();
+        let side = Char { content: vertical.into(), ..border };
 
-        self.render_frame(buf, y, '┌', '─', '┐');
+        self.render_frame(buf, y, border, [top_left, horizontal, top_right]);
+        self.render_title(buf, config, border, focused, mode);
         y += 1;
 
-        for row in &self.inner.screen {
-            buf.set(x, y, Char::from('│'));
-            buf.copy_from(x+1, y, &row);
-            buf.set(x+1+self.inner.width, y, Char::from('│'));
+        for (i, row) in self.inner.visible_rows().enumerate() {
+            if !buf.row_visible(y) {
+                y += 1;
+                continue;
+            }
+            let line = self.inner.line_at(i as u16);
+            // Scrollback rows keep the width they had when they scrolled off
+            let row = &row[..row.len().min(width)];
+
+            buf.set(x, y, side);
+            match self.inner.selection {
+                Some(selection) if selection.contains_line(line) => {
+                    let mut row = row.to_vec();
+                    for (col, c) in row.iter_mut().enumerate() {
+                        if selection.contains(line, col as u16) {
+                            c.flags ^= EFFECT_REVERSE;
+                        }
+                    }
+                    buf.copy_from(x+1, y, &row);
+                },
+                _ => buf.copy_from(x+1, y, row)
+            }
+            if row.len() < width {
+                buf.line(x+1 + row.len() as u16, y, (width - row.len()) as u16, SPACE);
+            }
+            buf.set(x+1+self.inner.width, y, side);
             y += 1;
         }
-        self.render_frame(buf, y, '└', '─', '┘');
+        self.render_frame(buf, y, border, [bottom_left, horizontal, bottom_right]);
+    }
+    /// Returns true if the frame covers this spot on the screen
+    pub fn covers(&self, x: u16, y: u16) -> bool {
+        x >= self.inner.x && x < self.inner.x + self.inner.width + 2
+            && y >= self.inner.y && y < self.inner.y + self.inner.height + 2
+    }
+    /// Returns true if the frame covers any of `rect`
+    pub fn covers_any(&self, rect: Rect) -> bool {
+        let frame = Rect {
+            x: self.inner.x,
+            y: self.inner.y,
+            width: self.inner.width + 2,
+            height: self.inner.height + 2
+        };
+        frame.intersection(rect).is_some()
+    }
+    /// Returns the program's cursor on the screen, unless it's hidden or
+    /// the view is scrolled away from it
+    pub fn cursor(&self) -> Option<Cursor> {
+        if self.inner.scroll != 0 {
+            return None;
+        }
+        let (x, y) = self.tracker.cursor()?;
+        Some(Cursor {
+            x: self.inner.x + 1 + x,
+            y: self.inner.y + 1 + y,
+            shape: self.tracker.cursor_shape
+        })
     }
     pub fn inside(&self, x: u16, y: u16) -> bool {
         let start_x = self.inner.x;
@@ -77,17 +251,40 @@ impl Window {
 
         self.inner.drag_offset.is_some()
             || self.inner.resize != 0
+            || self.inner.selecting
             || (x >= start_x && y >= start_y && x <= end_x && y <= end_y)
     }
-    pub fn click(&mut self, front: bool, m: u8, x: u16, y: u16) -> Result<()> {
+    /// Handle a mouse event. Returns the selected text once a selection is
+    /// finished. Windows can only be moved and resized when `floating`, and
+    /// dragging while holding the `select` modifier selects text.
+    pub fn click(&mut self, focused: bool, floating: bool, select: u8, mouse: Mouse) -> Result<Option<String>> {
+        let (x, y) = (mouse.x, mouse.y);
+
         if let Some((rel_x, rel_y)) = self.inner.drag_offset {
             self.inner.x = x.saturating_sub(rel_x);
             self.inner.y = y.saturating_sub(rel_y);
 
-            if m & 0b11 == 3 {
+            if mouse.release {
                 self.inner.drag_offset = None;
             }
-            return Ok(());
+            return Ok(None);
+        }
+        if self.inner.selecting {
+            // Keep the selection inside of the window, even if the pointer
+            // isn't
+            let col = x.saturating_sub(self.inner.x + 1).min(self.inner.width - 1);
+            let row = y.saturating_sub(self.inner.y + 1).min(self.inner.height - 1);
+            let line = self.inner.line_at(row);
+
+            let selection = self.inner.selection.as_mut().expect("selecting without a selection");
+            selection.end = (line, col);
+            let selection = *selection;
+
+            if mouse.release {
+                self.inner.selecting = false;
+                return Ok(Some(self.inner.selected_text(selection)));
+            }
+            return Ok(None);
         }
         if self.inner.resize != 0 {
             let mut width = self.inner.width;
@@ -104,36 +301,85 @@ impl Window {
             }
 
             self.resize(width, height)?;
-            #[cfg(feature = "signals")]
-            signal::kill(Pid::from_raw(self.inner.child.id() as _), signal::SIGWINCH)?;
 
-            if m & 0b11 == 3 {
+            if mouse.release {
                 self.inner.resize = 0;
             }
-            return Ok(());
+            return Ok(None);
         }
 
         let x = x - self.inner.x;
         let y = y - self.inner.y;
 
-        if y == 0 {
+        if y == 0 && floating {
             self.inner.drag_offset = Some((x, y));
-            return Ok(());
+            return Ok(None);
         }
 
-        if x == 0 {
-            self.inner.resize |= RESIZE_LEFT;
-        } else if x == 1+self.inner.width {
-            self.inner.resize |= RESIZE_RIGHT;
+        let content = x >= 1 && x <= self.inner.width && y >= 1 && y <= self.inner.height;
+        if mouse.press() {
+            self.inner.selection = None;
         }
-        if y == 1+self.inner.height {
-            self.inner.resize |= RESIZE_BOTTOM;
+        if content && mouse.press() && mouse.button & select == select && mouse.button & 0b11 == 0 {
+            let line = self.inner.line_at(y - 1);
+            self.inner.selection = Some(Selection {
+                start: (line, x - 1),
+                end: (line, x - 1)
+            });
+            self.inner.selecting = true;
+            return Ok(None);
         }
 
-        if self.inner.resize == 0 && front {
-            // 1-based
-            self.pty.write_all(&[b'\x1b', b'[', b'M', m, 32+x as u8, 32+y as u8])?;
+        if floating {
+            if x == 0 {
+                self.inner.resize |= RESIZE_LEFT;
+            } else if x == 1+self.inner.width {
+                self.inner.resize |= RESIZE_RIGHT;
+            }
+            if y == 1+self.inner.height {
+                self.inner.resize |= RESIZE_BOTTOM;
+            }
+        }
+
+        if self.inner.resize == 0 && focused {
+            self.report(mouse)?;
+        }
+        Ok(None)
+    }
+    /// Returns true if the program asked for this kind of mouse event
+    pub fn wants(&self, mouse: Mouse) -> bool {
+        match self.tracker.mouse.tracking {
+            MouseTracking::Off => false,
+            MouseTracking::Normal => !mouse.drag(),
+            MouseTracking::Button => !mouse.drag() || mouse.button & 0b11 != 3,
+            MouseTracking::Any => true
+        }
+    }
+    /// Returns true if the program wants to know about the pointer moving
+    /// even when no button is held
+    pub fn wants_motion(&self) -> bool {
+        self.tracker.mouse.tracking == MouseTracking::Any
+    }
+    /// Send a mouse event to the program if it asked for it, in the
+    /// encoding it asked for
+    pub fn report(&mut self, mouse: Mouse) -> io::Result<()> {
+        // A program that doesn't read its input won't miss a few reports
+        if !self.wants(mouse) || self.inner.pty.is_full() {
+            return Ok(());
         }
+        // The border makes these 1-based
+        let x = mouse.x.saturating_sub(self.inner.x).clamp(1, self.inner.width);
+        let y = mouse.y.saturating_sub(self.inner.y).clamp(1, self.inner.height);
+        self.inner.pty.write_all(&mouse.encode(self.tracker.mouse.encoding, x, y))
+    }
+    /// Handle the scroll wheel: Programs that asked for the mouse get it,
+    /// otherwise it scrolls through the scrollback
+    pub fn wheel(&mut self, mouse: Mouse, lines: isize) -> io::Result<()> {
+        if self.wants(mouse) {
+            return self.report(mouse);
+        }
+        // Buttons 4 and 5 are up and down
+        self.inner.scroll(if mouse.button & 1 == 0 { lines } else { -lines });
         Ok(())
     }
 }
@@ -150,8 +396,30 @@ impl DerefMut for Window {
     }
 }
 
+/// Like `pseudoterm::prepare_cmd`, but gives each standard stream its own
+/// file descriptor instead of closing the same one three times
+fn prepare_cmd(slave: File, cmd: &mut Command) -> io::Result<&mut Command> {
+    cmd.stdin(slave.try_clone()?)
+        .stdout(slave.try_clone()?)
+        .stderr(slave);
+    unsafe {
+        cmd.pre_exec(|| {
+            // Make process group leader
+            if libc::setsid() < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Make stdin (which is our slave) the controlling terminal
+            if libc::ioctl(0, libc::TIOCSCTTY as _, 1) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(cmd)
+}
+
 // The whole Inner thing is a workaround because the write() function needs to
-// borrow `self` which it can't do if it contains the console too.
+// borrow `self` which it can't do if it contains the tracker too.
 pub struct WindowInner {
     pub pty: DelayingWriter<File>,
     pub child: Child,
@@ -167,10 +435,25 @@ pub struct WindowInner {
 
     pub alternate: bool,
     pub screen: VecDeque<Vec<Char>>,
-    pub screen_other: VecDeque<Vec<Char>>
+    pub screen_other: VecDeque<Vec<Char>>,
+    pub scrollback: VecDeque<Vec<Char>>,
+    pub scroll: usize,
+
+    pub selection: Option<Selection>,
+    pub selecting: bool,
+
+    /// The title set by the program, using OSC 0 or 2
+    pub title: String,
+
+    /// The rows of the screen that changed since the window was last
+    /// rendered, as a start and an end
+    dirty: Option<(usize, usize)>,
+    /// The last region of the screen that scrolled since the window was
+    /// last rendered, as a start and an end, and how many lines up
+    scrolled: Option<(usize, usize, isize)>
 }
 impl WindowInner {
-    fn new(cmd: &OsStr, x: u16, y: u16, width: u16, height: u16) -> Result<Self> {
+    fn new(cmd: &mut Command, x: u16, y: u16, width: u16, height: u16) -> Result<Self> {
         // Open PTY
         let (pty, slave) = pseudoterm::openpty(&OpenptyOptions::new().with_nonblocking(true))?;
         // -> Set PTY size
@@ -181,7 +464,7 @@ impl WindowInner {
         })?;
         let pty = DelayingWriter::new(pty);
         // -> Start shell
-        let child = pseudoterm::prepare_cmd(slave, &mut Command::new(cmd))?.spawn()?;
+        let child = prepare_cmd(slave, cmd)?.spawn()?;
 
         // Prepare screen buffers
         let mut screen = VecDeque::with_capacity(height as usize * 4);
@@ -204,13 +487,133 @@ impl WindowInner {
 
             alternate: false,
             screen,
-            screen_other
+            screen_other,
+            scrollback: VecDeque::new(),
+            scroll: 0,
+
+            selection: None,
+            selecting: false,
+
+            title: String::new(),
+
+            dirty: Some((0, height as usize)),
+            scrolled: None
+        })
+    }
+    /// Returns the number of scrollback lines in front of the screen. The
+    /// alternate screen has no scrollback.
+    fn history(&self) -> usize {
+        if self.alternate { 0 } else { self.scrollback.len() }
+    }
+    /// Returns the line that is shown at row `y` of the window
+    pub fn line_at(&self, y: u16) -> usize {
+        let scroll = if self.alternate { 0 } else { self.scroll };
+        self.history() - scroll + y as usize
+    }
+    /// Returns a line from the scrollback followed by the screen
+    pub fn row(&self, line: usize) -> Option<&Vec<Char>> {
+        let history = self.history();
+        if line < history {
+            self.scrollback.get(line)
+        } else {
+            self.screen.get(line - history)
+        }
+    }
+    /// Returns the rows that should be shown, taking the scroll offset into
+    /// account
+    pub fn visible_rows(&self) -> impl Iterator<Item = &Vec<Char>> {
+        (0..self.height).filter_map(move |y| self.row(self.line_at(y)))
+    }
+    /// Returns the text on the screen, optionally preceded by the scrollback
+    pub fn text(&self, scrollback: bool) -> String {
+        let start = if scrollback { 0 } else { self.history() };
+        self.selected_text(Selection {
+            start: (start, 0),
+            end: (self.history() + self.height as usize - 1, self.width - 1)
+        })
+    }
+    /// Returns the text inside of a selection, without trailing whitespace
+    pub fn selected_text(&self, selection: Selection) -> String {
+        let (start, end) = selection.ordered();
+        let mut text = String::new();
+
+        for line in start.0..=end.0 {
+            let row = match self.row(line) {
+                Some(row) => row,
+                None => break
+            };
+            let from = if line == start.0 { start.1 as usize } else { 0 };
+            let to = if line == end.0 { end.1 as usize + 1 } else { row.len() };
+
+            let content: String = row.iter().take(to).skip(from).map(|c| c.content.to_string()).collect();
+            text.push_str(content.trim_end());
+            if line != end.0 {
+                text.push('\n');
+            }
+        }
+        text
+    }
+    /// Scroll the view `lines` lines up into the scrollback, or down if
+    /// negative. The alternate screen has no scrollback.
+    pub fn scroll(&mut self, lines: isize) {
+        if self.alternate {
+            return;
+        }
+        let scroll = self.scroll as isize + lines;
+        self.scroll = (scroll.max(0) as usize).min(self.scrollback.len());
+    }
+    fn push_scrollback(&mut self, row: Vec<Char>) {
+        if self.scrollback.len() >= SCROLLBACK_LIMIT {
+            self.scrollback.pop_front();
+            if let Some(selection) = &mut self.selection {
+                selection.start.0 = selection.start.0.saturating_sub(1);
+                selection.end.0 = selection.end.0.saturating_sub(1);
+            }
+        } else if self.scroll > 0 {
+            // Keep the view in place while new output arrives
+            self.scroll += 1;
+        }
+        self.scrollback.push_back(row);
+    }
+    /// Mark `height` rows from `y` as changed
+    fn touch(&mut self, y: usize, height: usize) {
+        let (start, end) = (y.min(self.height as usize - 1), (y + height).min(self.height as usize));
+        self.dirty = Some(match self.dirty {
+            Some((old_start, old_end)) => (old_start.min(start), old_end.max(end)),
+            None => (start, end)
+        });
+    }
+    /// Returns the area of the screen that changed since this was last
+    /// called
+    pub fn take_damage(&mut self) -> Option<Rect> {
+        let (mut start, mut end) = self.dirty.take()?;
+        if self.scroll != 0 {
+            // Rows of the view don't line up with the screen
+            start = 0;
+            end = self.height as usize;
+        }
+        Some(Rect {
+            x: self.x + 1,
+            y: self.y + 1 + start as u16,
+            width: self.width,
+            height: (end - start) as u16
         })
     }
+    /// Returns the area of the screen that scrolled since this was last
+    /// called, and how many lines up, if the view shows it
+    pub fn take_scroll(&mut self) -> Option<(Rect, isize)> {
+        let (start, end, lines) = self.scrolled.take()?;
+        if self.scroll != 0 || lines == 0 {
+            return None;
+        }
+        Some((Rect {
+            x: self.x + 1,
+            y: self.y + 1 + start as u16,
+            width: self.width,
+            height: (end - start) as u16
+        }, lines))
+    }
     fn get(&mut self, x: usize, y: usize) -> &mut Char {
-        // TODO: Scrollback?
-        // let screen_start = self.screen.len() - self.height as usize;
-
         self.screen
             .get_mut(y.min(self.height as usize - 1)).expect("invalid y in get() call")
             .get_mut(x.min(self.width as usize - 1)).expect("invalid x in get() call")
@@ -218,6 +621,8 @@ impl WindowInner {
     fn resize(&mut self, width: u16, height: u16) -> Result<()> {
         self.width = width;
         self.height = height;
+        self.dirty = Some((0, height as usize));
+        self.scrolled = None;
 
         for screen in &mut [&mut self.screen, &mut self.screen_other] {
             for row in &mut **screen {
@@ -232,34 +637,45 @@ impl WindowInner {
         })?;
         Ok(())
     }
-    fn write(&mut self, console: &mut Console, buf: &[u8]) {
-        console.write(buf, |event| match event {
-            Event::Char { x, y, c: content, bold, underlined, color } => {
+    fn write(&mut self, tracker: &mut Tracker, buf: &[u8]) {
+        tracker.write(buf, |output, pen| match output {
+            // ransid's colors are ignored in favor of the pen's, which can be
+            // the host's default
+            Output::Print { x, y, content } => {
+                self.touch(y, 1);
                 let c = self.get(x, y);
                 c.content = content;
-                c.flags = if bold { EFFECT_BOLD } else { 0 }
-                    | if underlined { EFFECT_UNDERLINE } else { 0 };
-                c.fg = color;
+                c.flags = pen.flags;
+                c.fg = pen.fg;
+                c.underline = pen.underline;
             },
-            Event::Rect { x, y, w, h, color } => {
+            // Sent as `Output::Print` instead
+            Output::Ransid(Event::Char { .. }) => (),
+            Output::Ransid(Event::Rect { x, y, w, h, .. }) => {
+                self.touch(y, h);
                 for x in x..x+w {
                     for y in y..y+h {
-                        let c = self.get(x, y);
-                        c.content = ' ';
-                        c.bg = color;
+                        *self.get(x, y) = Char { bg: pen.bg, ..SPACE };
                     }
                 }
             },
-            Event::ScreenBuffer { alternate, clear } => {
+            Output::Ransid(Event::ScreenBuffer { alternate, clear }) => {
+                self.touch(0, self.height as usize);
                 if self.alternate != alternate {
                     self.alternate = alternate;
                     mem::swap(&mut self.screen, &mut self.screen_other);
                 }
                 if clear {
-                    // TODO: Scrollback?
-                    // let scroll_len = self.screen.len() - self.height as usize;
-                    // self.screen.drain(..scroll_len);
-
+                    if !self.alternate {
+                        // Blank rows below the last output aren't worth keeping
+                        let used = self.screen.iter()
+                            .rposition(|row| row.iter().any(|c| *c != SPACE))
+                            .map_or(0, |y| y + 1);
+                        for i in 0..used {
+                            let row = self.screen[i].clone();
+                            self.push_scrollback(row);
+                        }
+                    }
                     for row in &mut self.screen {
                         for col in row {
                             *col = SPACE;
@@ -267,19 +683,85 @@ impl WindowInner {
                     }
                 }
             },
-            Event::Move { from_x, from_y, to_x, to_y, w, h } => {
-                for rel_x in 0..w {
-                    for rel_y in 0..h {
-                        let rel_x = if to_x <= from_x { rel_x } else { w - rel_x };
-                        let rel_y = if to_y <= from_y { rel_y } else { h - rel_y };
-
-                        *self.get(from_x + rel_x, from_y + rel_y) = self.get(to_x + rel_x, to_y + rel_y).clone();
+            Output::Ransid(Event::Move { from_x, from_y, to_x, to_y, w, h }) => {
+                self.touch(to_y, h);
+                if from_x == 0 && to_x == 0 && w == self.width as usize && from_y != to_y {
+                    let (start, end) = (from_y.min(to_y), from_y.max(to_y) + h);
+                    let lines = from_y as isize - to_y as isize;
+                    self.scrolled = Some(match self.scrolled {
+                        Some((old_start, old_end, old)) if (old_start, old_end) == (start, end) => (start, end, old + lines),
+                        // Only the last region is scrolled on the host, the
+                        // rest is drawn like anything else
+                        _ => (start, end, lines)
+                    });
+                }
+                if !self.alternate && from_x == 0 && to_x == 0 && to_y == 0 && from_y > 0 {
+                    // The top lines are about to be overwritten, which is
+                    // what scrolling looks like
+                    for y in 0..from_y.min(self.screen.len()) {
+                        let row = self.screen[y].clone();
+                        self.push_scrollback(row);
+                    }
+                }
+                // Copy in the opposite direction of the move so nothing gets
+                // overwritten before it has been copied
+                for rel_y in 0..h {
+                    let rel_y = if to_y <= from_y { rel_y } else { h - 1 - rel_y };
+                    for rel_x in 0..w {
+                        let rel_x = if to_x <= from_x { rel_x } else { w - 1 - rel_x };
+
+                        *self.get(to_x + rel_x, to_y + rel_y) = *self.get(from_x + rel_x, from_y + rel_y);
                     }
                 }
             },
             // panics because i can't return errors here              vvvvvv
-            Event::Resize { w, h } => self.resize(w as u16, h as u16).unwrap(),
-            Event::Title { .. } | Event::Input { .. } => ()
+            Output::Ransid(Event::Resize { w, h }) => self.resize(w as u16, h as u16).unwrap(),
+            Output::Ransid(Event::Title { title }) => self.title = title.chars().filter(|c| !c.is_control()).collect(),
+            // Answers to queries like the cursor position. If the pty is
+            // broken, reading from it fails and the window goes away anyway.
+            // A program that asks without reading the answers gets none.
+            Output::Ransid(Event::Input { data }) => if !self.pty.is_full() {
+                let _ = self.pty.write_all(data);
+            }
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(width: u16, height: u16) -> Window {
+        Window::new(&mut Command::new("true"), &Config::default(), 0, 0, width + 2, height + 2).unwrap()
+    }
+
+    #[test]
+    fn click_tiled_border() {
+        let mut window = window(10, 4);
+        let alt = 8;
+        // Tiled windows can't be dragged by their title, and selecting
+        // only starts inside of the border
+        let mouse = Mouse { button: alt, x: 3, y: 0, release: false };
+        assert_eq!(window.click(true, false, alt, mouse).unwrap(), None);
+        assert!(!window.selecting && window.selection.is_none());
+
+        let mouse = Mouse { button: alt, x: 3, y: 1, release: false };
+        window.click(true, false, alt, mouse).unwrap();
+        assert!(window.selecting);
+        window.child.wait().unwrap();
+    }
+    #[test]
+    fn clear_scrollback() {
+        let mut window = window(10, 5);
+        // Leaving the alternate screen like this clears the other one
+        let clear = b"\x1b[?1047h\x1b[?1047l";
+        window.write(b"one\r\ntwo\r\n\r\nthree");
+        window.write(clear);
+        assert_eq!(window.scrollback.len(), 4);
+        assert!(window.screen.iter().flatten().all(|c| *c == SPACE));
+        // Nothing to keep the second time around
+        window.write(clear);
+        assert_eq!(window.scrollback.len(), 4);
+        window.child.wait().unwrap();
+    }
+}
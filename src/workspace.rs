@@ -1,110 +1,380 @@
 use super::{
     buffer::{Buffer, Char},
-    window::Window,
+    config::{Config, Placement},
+    input::Command,
+    key::Mouse,
+    layout::{Layout, Rect},
+    window::{Selection, Window},
     Result
 };
 
 use linked_hash_map::LinkedHashMap;
 use mio::{*, unix::EventedFd};
 use std::{
-    ffi::OsStr,
     io::{self, prelude::*},
-    os::unix::io::AsRawFd
+    os::unix::io::AsRawFd,
+    process::Command as Process
 };
 
+/// The things about a window that change how its frame is rendered
+#[derive(PartialEq)]
+struct Look {
+    key: Token,
+    frame: Rect,
+    focused: bool,
+    mode: Option<&'static str>,
+    title: String,
+    scroll: usize,
+    selection: Option<Selection>
+}
+
 pub struct Workspace<'a> {
     pub buffer: Buffer,
     pub poll: Poll,
-    pub shell: &'a OsStr,
+    pub config: &'a Config,
     pub token: Token,
-    pub windows: LinkedHashMap<Token, Window>
+    /// Windows in stacking order, the last one being on top
+    pub windows: LinkedHashMap<Token, Window>,
+    /// The window that receives keyboard input
+    pub focus: Option<Token>,
+    pub layout: Layout,
+    /// How each window looked when it was last rendered, in stacking order
+    looks: Vec<Look>,
+    /// What the keys do, shown in the focused window's title, see
+    /// `Parser::mode`
+    pub mode: Option<&'static str>,
+    pub detach: bool,
+    pub quit: bool
 }
 impl<'a> Workspace<'a> {
-    pub fn new(shell: &'a OsStr, token_offset: Token, width: u16, height: u16) -> Result<Self> {
+    pub fn new(config: &'a Config, token_offset: Token, width: u16, height: u16) -> Result<Self> {
         Ok(Self {
             buffer: Buffer::new(width, height),
             poll: Poll::new()?,
-            shell,
+            config,
             token: token_offset,
-            windows: LinkedHashMap::new()
+            windows: LinkedHashMap::new(),
+            focus: None,
+            layout: Layout::default(),
+            looks: Vec::new(),
+            mode: None,
+            detach: false,
+            quit: false
         })
     }
-    #[cfg(feature = "signals")]
-    pub fn resize(&mut self, width: u16, height: u16) {
+    pub fn resize(&mut self, width: u16, height: u16) -> Result<()> {
         self.buffer.resize(width, height);
         // Move all windows that would be outside of the screen
         for (_, window) in &mut self.windows {
             window.x = window.x.min(width-1);
             window.y = window.y.min(height-1);
         }
+        self.arrange()
     }
-    pub fn add(&mut self, window: Window) -> Result<()> {
-        self.poll.register(&EventedFd(&window.pty.as_raw_fd()), self.token, Ready::readable() | Ready::writable(), PollOpt::edge())?;
-        self.windows.insert(self.token, window);
-        self.token = Token(usize::from(self.token) + 1);
+    /// Let the layout decide the geometry of all windows
+    pub fn arrange(&mut self) -> Result<()> {
+        let rects = self.layout.arrange(self.windows.len(), self.buffer.width, self.buffer.height);
+        for ((_, window), rect) in self.windows.iter_mut().rev().zip(rects.into_iter().flatten()) {
+            window.set_frame(rect)?;
+        }
         Ok(())
     }
+    pub fn add(&mut self, window: Window) -> Result<Token> {
+        let key = self.token;
+        self.poll.register(&EventedFd(&window.pty.as_raw_fd()), key, Ready::readable() | Ready::writable(), PollOpt::edge())?;
+        self.windows.insert(key, window);
+        self.focus = Some(key);
+        self.token = Token(usize::from(key) + 1);
+        self.arrange()?;
+        Ok(key)
+    }
     pub fn remove(&mut self, key: Token) -> Result<()> {
         if let Some(window) = self.windows.remove(&key) {
             self.poll.deregister(&EventedFd(&window.pty.as_raw_fd()))?;
         }
+        if self.focus == Some(key) {
+            self.focus = self.windows.keys().next_back().cloned();
+        }
+        self.arrange()
+    }
+    /// Returns the frame of a new window that doesn't ask for anything else,
+    /// optionally spawned by clicking at `pointer`
+    pub fn default_frame(&self, pointer: Option<(u16, u16)>) -> Rect {
+        let (screen_width, screen_height) = (self.buffer.width, self.buffer.height);
+        let width = self.config.window.width.clamp(3, screen_width.max(3));
+        let height = self.config.window.height.clamp(3, screen_height.max(3));
+        let center = (
+            (screen_width / 2).saturating_sub(width / 2),
+            (screen_height / 2).saturating_sub(height / 2)
+        );
+
+        let (x, y) = match (self.config.window.placement, pointer) {
+            (Placement::Center, _) | (Placement::Pointer, None) => center,
+            (Placement::Cascade, _) => match self.windows.back() {
+                Some((_, top)) => (top.x + 2, top.y + 1),
+                None => center
+            },
+            (Placement::Pointer, Some(pointer)) => pointer
+        };
+        // Start over from the corner rather than falling off the screen
+        Rect {
+            x: if x + width > screen_width { 0 } else { x },
+            y: if y + height > screen_height { 0 } else { y },
+            width,
+            height
+        }
+    }
+    /// Spawn a shell
+    pub fn spawn(&mut self, pointer: Option<(u16, u16)>) -> Result<Token> {
+        let frame = self.default_frame(pointer);
+        self.spawn_at(&mut self.config.shell(), frame)
+    }
+    pub fn spawn_at(&mut self, cmd: &mut Process, frame: Rect) -> Result<Token> {
+        let window = Window::new(cmd, self.config, frame.x, frame.y, frame.width.max(3), frame.height.max(3))?;
+        self.add(window)
+    }
+    /// Move a window to the top of the stack
+    pub fn raise(&mut self, key: Token) {
+        if let Some(window) = self.windows.remove(&key) {
+            self.windows.insert(key, window);
+        }
+    }
+    /// Move the focus `offset` steps through the stack, wrapping around
+    pub fn cycle_focus(&mut self, offset: isize) {
+        let len = self.windows.len() as isize;
+        if len == 0 {
+            return;
+        }
+        let current = self.windows.keys()
+            .position(|&key| Some(key) == self.focus)
+            .unwrap_or(0) as isize;
+        let next = (current + offset).rem_euclid(len);
+        self.focus = self.windows.keys().nth(next as usize).cloned();
+
+        if self.layout == Layout::Monocle {
+            // Only the top window is visible
+            if let Some(key) = self.focus {
+                self.raise(key);
+            }
+        }
+    }
+    /// Returns true if input should wait for the focused window to read
+    /// what it already got
+    pub fn input_blocked(&self) -> bool {
+        self.focus
+            .and_then(|key| self.windows.get(&key))
+            .is_some_and(|window| window.pty.is_full())
+    }
+    pub fn command(&mut self, command: Command) -> Result<()> {
+        let (width, height) = (self.buffer.width, self.buffer.height);
+        let floating = self.layout == Layout::Floating;
+        match command {
+            Command::Spawn => {
+                self.spawn(None)?;
+            },
+            Command::Close => if let Some(window) = self.focus.and_then(|key| self.windows.get_mut(&key)) {
+                window.close()?;
+            },
+            Command::FocusNext => self.cycle_focus(1),
+            Command::FocusPrev => self.cycle_focus(-1),
+            Command::Move(x, y) => if let Some(window) = self.focus.and_then(|key| self.windows.get_mut(&key)) {
+                if floating {
+                    window.x = (window.x as i32 + x as i32).clamp(0, width as i32 - 1) as u16;
+                    window.y = (window.y as i32 + y as i32).clamp(0, height as i32 - 1) as u16;
+                }
+            },
+            Command::Resize(x, y) => if let Some(window) = self.focus.and_then(|key| self.windows.get_mut(&key)) {
+                if floating {
+                    let width = (window.width as i32 + x as i32).max(1) as u16;
+                    let height = (window.height as i32 + y as i32).max(1) as u16;
+                    window.resize(width, height)?;
+                }
+            },
+            Command::Raise => if let Some(key) = self.focus {
+                // In tiling layouts this makes the window the master
+                self.raise(key);
+                self.arrange()?;
+            },
+            Command::NextLayout => {
+                self.layout = self.layout.next();
+                self.arrange()?;
+            },
+            Command::Detach => self.detach = true,
+            Command::Quit => self.quit = true
+        }
+        Ok(())
+    }
+    pub fn mouse(&mut self, mut mouse: Mouse) -> Result<()> {
+        mouse.x = mouse.x.min(self.buffer.width-1);
+        mouse.y = mouse.y.min(self.buffer.height-1);
+
+        if mouse.wheel() {
+            let lines = self.config.mouse.scroll_lines as isize;
+            if let Some((_, window)) = self.windows.iter_mut().rev().find(|(_, w)| w.inside(mouse.x, mouse.y)) {
+                window.wheel(mouse, lines)?;
+            }
+        } else if mouse.drag() && mouse.button & 0b11 == 3 {
+            // The pointer moved without any button held, which only matters
+            // to the focused program, if it asked for it
+            if let Some(window) = self.focus.and_then(|key| self.windows.get_mut(&key)) {
+                if window.inside(mouse.x, mouse.y) {
+                    window.report(mouse)?;
+                }
+            }
+        } else {
+            self.click(mouse)?;
+        }
         Ok(())
     }
-    pub fn click(&mut self, m: u8, x: u8, y: u8) -> Result<()> {
-        // 1-based, but we want 0-based
-        let x = (x.saturating_sub(0o40 + 1) as u16).min(self.buffer.width-1);
-        let y = (y.saturating_sub(0o40 + 1) as u16).min(self.buffer.height-1);
+    fn click(&mut self, mouse: Mouse) -> Result<()> {
+        let (x, y) = (mouse.x, mouse.y);
 
         if let Some((&key, _)) = self.windows.iter_mut().rev().find(|(_, w)| w.inside(x, y)) {
             let front = *self.windows.back().unwrap().0 == key;
+            let focused = self.focus == Some(key);
+            let floating = self.layout == Layout::Floating;
 
+            let select = self.config.mouse.select.mask();
             let window = &mut self.windows[&key];
-            window.click(front, m, x, y)?;
+            if let Some(text) = window.click(focused, floating, select, mouse)? {
+                self.buffer.clipboard = Some(text);
+            }
 
-            // Move window to front if the button is released or it's being
-            // dragged
-            if !front && (m & 0x40 == 0x40 || m & 0b11 == 3) {
-                let win = self.windows.remove(&key).unwrap();
-                self.windows.insert(key, win);
+            // Focus the window, and move it to front if floating, when the
+            // button is released or it's being dragged
+            if mouse.drag() || mouse.release {
+                if !front && floating {
+                    self.raise(key);
+                }
+                self.focus = Some(key);
+            }
+        } else if mouse.release {
+            // They clicked anywhere on the screen, by default that spawns a
+            // terminal
+            match self.config.mouse.background {
+                Some(Command::Spawn) => {
+                    self.spawn(Some((x, y)))?;
+                },
+                Some(command) => self.command(command)?,
+                None => ()
             }
-        } else if m & 0b11 == 3 {
-            // They clicked anywhere on the screen, let's spawn a terminal
-            let width = 80.min(self.buffer.width);
-            let height = 32.min(self.buffer.height);
-            self.add(Window::new(
-                self.shell,
-                self.buffer.width / 2 - width / 2,
-                self.buffer.height / 2 - height / 2,
-                width,
-                height
-            )?)?;
         }
         Ok(())
     }
+    /// Returns how each window looks now, in stacking order
+    fn looks(&self) -> Vec<Look> {
+        self.windows.iter().map(|(&key, window)| Look {
+            key,
+            frame: Rect {
+                x: window.x,
+                y: window.y,
+                width: window.width + 2,
+                height: window.height + 2
+            },
+            focused: self.focus == Some(key),
+            mode: if self.focus == Some(key) { self.mode } else { None },
+            title: window.title.clone(),
+            scroll: window.scroll,
+            selection: window.selection
+        }).collect()
+    }
+    /// Compose the parts of the screen that changed since the last time
     pub fn render(&mut self) {
-        self.buffer.clear();
+        // A window that moved, changed or went somewhere else in the stack
+        // is rendered again where it was and where it is
+        let looks = self.looks();
+        let mut scrolls = Vec::new();
+        for ((i, look), (_, window)) in looks.iter().enumerate().zip(self.windows.iter_mut()) {
+            let scroll = window.take_scroll();
+            if self.looks.get(i) == Some(look) {
+                scrolls.extend(scroll.map(|scroll| (i, scroll)));
+            } else {
+                self.buffer.damage(look.frame);
+                if let Some(old) = self.looks.iter().find(|old| old.key == look.key) {
+                    self.buffer.damage(old.frame);
+                }
+            }
+        }
+        for old in &self.looks {
+            if !looks.iter().any(|look| look.key == old.key) {
+                self.buffer.damage(old.frame);
+            }
+        }
+        // Scrolling windows are scrolled on the host if nothing is on top
+        // of them. A window as wide as the screen takes its border along,
+        // which works even where only whole rows can be scrolled.
+        for (i, (mut rect, lines)) in scrolls {
+            let frame = looks[i].frame;
+            if frame.x == 0 && frame.width == self.buffer.width {
+                rect.x = 0;
+                rect.width = frame.width;
+            }
+            if !self.windows.iter().skip(i + 1).any(|(_, window)| window.covers_any(rect)) {
+                self.buffer.scroll(rect, lines);
+            }
+        }
+        self.looks = looks;
+        for (_, window) in &mut self.windows {
+            if let Some(rect) = window.take_damage() {
+                self.buffer.damage(rect);
+            }
+        }
 
-        let x = self.buffer.width / 2 - "Click anywhere!".len() as u16 / 2;
+        let text = "Click anywhere!";
+        // Left out on screens too narrow for it
+        let fits = text.len() as u16 <= self.buffer.width;
+        let x = (self.buffer.width / 2).saturating_sub(text.len() as u16 / 2);
         let y = self.buffer.height / 2;
-        for (i, c) in "Click anywhere!".chars().enumerate() {
-            self.buffer.set(x + i as u16, y, Char::from(c));
+        for rect in self.buffer.damaged() {
+            self.buffer.set_clip(Some(rect));
+            self.buffer.clear();
+            if fits {
+                for (i, c) in text.chars().enumerate() {
+                    self.buffer.set(x + i as u16, y, Char::from(c));
+                }
+            }
+            for (&key, window) in &self.windows {
+                if window.covers_any(rect) {
+                    let focused = self.focus == Some(key);
+                    window.render(&mut self.buffer, self.config, focused, if focused { self.mode } else { None });
+                }
+            }
         }
+        self.buffer.set_clip(None);
 
-        for window in self.windows.values() {
-            window.render(&mut self.buffer);
-        }
+        // Only show the cursor if nothing is on top of it
+        self.buffer.cursor = self.focus.and_then(|focus| {
+            let mut above = self.windows.iter().skip_while(|&(&key, _)| key != focus);
+            let cursor = above.next()?.1.cursor()?;
+            if cursor.x >= self.buffer.width || cursor.y >= self.buffer.height
+                    || above.any(|(_, window)| window.covers(cursor.x, cursor.y)) {
+                return None;
+            }
+            Some(cursor)
+        });
+        self.buffer.mouse_motion = self.focus
+            .and_then(|key| self.windows.get(&key))
+            .is_some_and(|window| window.wants_motion());
     }
 }
 impl<'a> Write for Workspace<'a> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self.windows.iter_mut().next_back() {
-            Some((_, window)) => window.pty.write(buf),
+        match self.focus.and_then(|key| self.windows.get_mut(&key)) {
+            Some(window) => {
+                // Typing snaps the view back to the live output
+                window.scroll = 0;
+                // Input is held back while the pty is full, see
+                // `input_blocked`, so this only goes over by what was already
+                // on its way
+                window.pty.push(buf)?;
+                Ok(buf.len())
+            },
             None => Ok(buf.len())
         }
     }
     fn flush(&mut self) -> io::Result<()> {
-        match self.windows.iter_mut().next_back() {
-            Some((_, window)) => window.pty.flush(),
+        match self.focus.and_then(|key| self.windows.get_mut(&key)) {
+            Some(window) => window.pty.flush(),
             None => Ok(())
         }
     }